@@ -0,0 +1,147 @@
+//! Measures the cost of preparing and executing a request against a generated site,
+//! comparing copying the whole context per request with sharing it, and copying the runtime with reusing pooled ones.
+//!
+//! Run with `cargo bench --bench request`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::Request;
+use garnish_lang::simple::{SimpleGarnishData, SimpleGarnishRuntime};
+use garnish_lang::{GarnishContext, GarnishData, GarnishRuntime};
+use garnish_web_server::context::SharedContext;
+use garnish_web_server::{execute_runtime, handler, SharedState, WebServerBuilder};
+
+const PAGE_COUNT: usize = 200;
+const ITERATIONS: usize = 500;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn main() {
+    let state = build_site();
+    let start = state
+        .route_mapping()
+        .get("page_0")
+        .expect("Route page_0 not compiled")
+        .execution_start();
+
+    println!(
+        "{} pages, {} instructions, {} data items, {} metadata entries, {} iterations",
+        PAGE_COUNT,
+        state.runtime().get_data().get_instruction_len(),
+        state.runtime().get_data().get_data_len(),
+        state.context().metadata().len(),
+        ITERATIONS
+    );
+    println!();
+
+    measure("before: copy runtime and context", || {
+        let mut runtime = state.runtime().clone();
+        let mut context = state.context().clone();
+        execute(&mut runtime, &mut context, start);
+    });
+
+    measure("after: copy runtime, share context", || {
+        let mut runtime = state.runtime().clone();
+        let mut context = SharedContext::new(state.context());
+        execute(&mut runtime, &mut context, start);
+    });
+
+    measure("after: pooled runtime, share context", || {
+        let mut runtime = state.runtimes().take(state.runtime());
+        let mut context = SharedContext::new(state.context());
+        execute(&mut runtime, &mut context, start);
+        state.runtimes().put(runtime, state.runtime());
+    });
+
+    let tokio_runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    measure("after: full handler", || {
+        let request = Request::builder()
+            .uri("/page_0")
+            .body(Body::empty())
+            .unwrap();
+
+        tokio_runtime.block_on(handler(State(state.clone()), request));
+    });
+}
+
+fn build_site() -> Arc<SharedState> {
+    let mut builder = WebServerBuilder::new();
+
+    for i in 0..PAGE_COUNT {
+        builder = builder.source(
+            format!("page_{}.garnish", i),
+            format!(
+                "@Def \"title_{i}\" {{\n    \"Page {i}\"\n}}\n\nhtml` ( head` title` text` title_{i}, body` ( h1` text` title_{i}, p` text` \"Generated page\" ) )\n",
+                i = i
+            ),
+        );
+    }
+
+    builder.build().expect("Failed to build site")
+}
+
+fn execute<C: GarnishContext<SimpleGarnishData>>(
+    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
+    context: &mut C,
+    start: usize,
+) {
+    runtime.get_data_mut().set_instruction_cursor(start).unwrap();
+    // only setup cost is compared, so failures are ignored
+    let _ = execute_runtime(runtime, context);
+}
+
+fn measure<F: FnMut()>(name: &str, mut f: F) {
+    // warm up
+    for _ in 0..10 {
+        f();
+    }
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let mut times = Vec::with_capacity(ITERATIONS);
+
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        f();
+        times.push(start.elapsed());
+    }
+
+    let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / ITERATIONS;
+    let bytes = (ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes) / ITERATIONS;
+
+    times.sort();
+    let mean = times.iter().sum::<Duration>() / ITERATIONS as u32;
+    let p99 = times[ITERATIONS * 99 / 100];
+
+    println!("{}", name);
+    println!(
+        "    {:>8} allocations/request  {:>10} bytes/request  mean {:>10?}  p99 {:>10?}",
+        allocations, bytes, mean, p99
+    );
+}
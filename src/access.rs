@@ -0,0 +1,455 @@
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use axum::body::{Body, HttpBody};
+use axum::extract::{ConnectInfo, State};
+use axum::http::header::{CONTENT_LENGTH, REFERER, USER_AGENT};
+use axum::http::{HeaderMap, HeaderValue, Request};
+use axum::middleware::{from_fn_with_state, Next};
+use axum::response::Response;
+use axum::Router;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::config::AccessLogConfig;
+use crate::RouteKey;
+
+/// Header carrying a request's ID. Kept from the request when present, otherwise generated, and added to the response.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Line format of the access log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// NCSA common log format.
+    Common,
+    /// Common log format followed by the referer and user agent.
+    #[default]
+    Combined,
+    /// One JSON object per line, with every recorded field.
+    Json,
+}
+
+impl Display for AccessLogFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AccessLogFormat::Common => "common",
+            AccessLogFormat::Combined => "combined",
+            AccessLogFormat::Json => "json",
+        })
+    }
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err(format!(
+                "Expected access log format of common, combined or json. Found {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// One request, as written to the access log.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessEntry {
+    /// Time the request arrived, in RFC 3339 format.
+    pub time: String,
+    pub request_id: String,
+    pub client: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    /// Route key the request matched. Not set for static files and unmatched requests.
+    pub route: Option<String>,
+    pub status: u16,
+    /// Size of the response body before compression, when known.
+    pub bytes: Option<u64>,
+    pub duration_ms: f64,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    #[serde(skip)]
+    received: SystemTime,
+}
+
+impl AccessEntry {
+    /// Formats the entry as a single line, without a line ending.
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => self.common(),
+            AccessLogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                self.common(),
+                quoted(self.referer.as_deref()),
+                quoted(self.user_agent.as_deref())
+            ),
+            AccessLogFormat::Json => serde_json::to_string(self).unwrap_or_else(|e| {
+                format!("{{\"error\":\"Failed to serialize access log entry. Reason: {}\"}}", e)
+            }),
+        }
+    }
+
+    fn common(&self) -> String {
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {}",
+            self.client.as_deref().unwrap_or("-"),
+            common_time(self.received),
+            self.method,
+            quoted(Some(&self.path)),
+            self.version,
+            self.status,
+            self.bytes
+                .map(|b| b.to_string())
+                .unwrap_or_else(|| String::from("-"))
+        )
+    }
+}
+
+/// Writes access log lines to stdout or a size rotated file.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    trust_forwarded: bool,
+    output: Mutex<AccessOutput>,
+}
+
+enum AccessOutput {
+    Stdout,
+    File(RotatingFile),
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Result<Self, String> {
+        let output = match &config.file {
+            None => AccessOutput::Stdout,
+            Some(path) => AccessOutput::File(RotatingFile::open(
+                path,
+                config.max_file_bytes,
+                config.max_files,
+            )?),
+        };
+
+        Ok(Self {
+            format: config.format,
+            trust_forwarded: config.trust_forwarded,
+            output: Mutex::new(output),
+        })
+    }
+
+    pub fn write(&self, entry: &AccessEntry) {
+        let line = format!("{}\n", entry.format(self.format));
+
+        let result = match &mut *self.output.lock().unwrap() {
+            AccessOutput::Stdout => std::io::stdout().lock().write_all(line.as_bytes()),
+            AccessOutput::File(file) => file.write_line(&line),
+        };
+
+        if let Err(e) = result {
+            error!("Failed to write access log. Reason: {}", e);
+        }
+    }
+}
+
+/// File renamed with an increasing numeric suffix once it grows past a size.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64, max_files: usize) -> Result<Self, String> {
+        let file = open_append(path)?;
+        let size = file
+            .metadata()
+            .or_else(|e| Err(format!("Failed to read metadata of {:?}. Reason: {}", path, e)))?
+            .len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            if let Err(e) = self.rotate() {
+                error!("Failed to rotate access log {:?}. Reason: {}", self.path, e);
+            }
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), String> {
+        match self.max_files {
+            0 => std::fs::remove_file(&self.path).or_else(|e| Err(e.to_string()))?,
+            max => {
+                for i in (1..max).rev() {
+                    let from = self.numbered(i);
+                    if from.exists() {
+                        std::fs::rename(&from, self.numbered(i + 1))
+                            .or_else(|e| Err(e.to_string()))?;
+                    }
+                }
+
+                std::fs::rename(&self.path, self.numbered(1)).or_else(|e| Err(e.to_string()))?;
+            }
+        }
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+
+    fn numbered(&self, i: usize) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{}", i));
+        PathBuf::from(name)
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .or_else(|e| Err(format!("Failed to open access log {:?}. Reason: {}", path, e)))
+}
+
+/// Adds access logging of every request to the app, when enabled.
+pub fn with_access_log(app: Router, config: &AccessLogConfig) -> Result<Router, String> {
+    if !config.enabled {
+        return Ok(app);
+    }
+
+    let log = Arc::new(AccessLog::new(config)?);
+
+    Ok(app.layer(from_fn_with_state(log, access_log_middleware)))
+}
+
+async fn access_log_middleware(
+    State(log): State<Arc<AccessLog>>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let received = SystemTime::now();
+    let start = Instant::now();
+
+    let request_id = match request.headers().get(REQUEST_ID_HEADER) {
+        Some(id) => id.clone(),
+        None => {
+            // generated IDs are hex digits, which are always a valid header value
+            let id = HeaderValue::from_str(&next_request_id()).unwrap();
+            request.headers_mut().insert(REQUEST_ID_HEADER, id.clone());
+            id
+        }
+    };
+
+    let client = client_address(&request, log.trust_forwarded);
+    let method = request.method().to_string();
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.to_string())
+        .unwrap_or_else(|| String::from("/"));
+    let version = format!("{:?}", request.version());
+    let referer = header_text(request.headers(), REFERER.as_str());
+    let user_agent = header_text(request.headers(), USER_AGENT.as_str());
+
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, request_id.clone());
+
+    let bytes = response.body().size_hint().exact().or_else(|| {
+        header_text(response.headers(), CONTENT_LENGTH.as_str()).and_then(|l| l.parse().ok())
+    });
+
+    log.write(&AccessEntry {
+        time: rfc3339_time(received),
+        request_id: String::from_utf8_lossy(request_id.as_bytes()).to_string(),
+        client,
+        method,
+        path,
+        version,
+        route: response.extensions().get::<RouteKey>().map(|key| key.0.clone()),
+        status: response.status().as_u16(),
+        bytes,
+        duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+        referer,
+        user_agent,
+        received,
+    });
+
+    response
+}
+
+fn client_address(request: &Request<Body>, trust_forwarded: bool) -> Option<String> {
+    let forwarded = match trust_forwarded {
+        true => header_text(request.headers(), FORWARDED_FOR_HEADER)
+            .and_then(|h| h.split(',').next().map(|a| a.trim().to_string()))
+            .filter(|a| !a.is_empty()),
+        false => None,
+    };
+
+    forwarded.or_else(|| {
+        request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip().to_string())
+    })
+}
+
+fn header_text(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.to_string())
+}
+
+/// Unique for the life of the process, prefixed with the start time so IDs differ across restarts.
+fn next_request_id() -> String {
+    static PREFIX: OnceLock<u64> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let prefix = PREFIX.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    });
+
+    format!("{:x}-{:x}", prefix, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Escapes quotes and backslashes so the value can't end its quoted log field early.
+fn quoted(value: Option<&str>) -> String {
+    match value {
+        None => String::from("-"),
+        Some(value) => value.replace('\\', "\\\\").replace('"', "\\\""),
+    }
+}
+
+/// Day, month, year and time parts of an HTTP date like 'Sun, 06 Nov 1994 08:49:37 GMT'.
+fn date_parts(time: SystemTime) -> (String, String, String, String) {
+    let date = httpdate::fmt_http_date(time);
+    let parts: Vec<&str> = date.split_whitespace().collect();
+
+    match parts.as_slice() {
+        [_, day, month, year, time, _] => (
+            day.to_string(),
+            month.to_string(),
+            year.to_string(),
+            time.to_string(),
+        ),
+        _ => Default::default(),
+    }
+}
+
+/// Time as used by the common log format, like '06/Nov/1994:08:49:37 +0000'.
+fn common_time(time: SystemTime) -> String {
+    let (day, month, year, time) = date_parts(time);
+    format!("{}/{}/{}:{} +0000", day, month, year, time)
+}
+
+fn rfc3339_time(time: SystemTime) -> String {
+    let (day, month, year, time) = date_parts(time);
+    let month = MONTHS.iter().position(|m| *m == month).unwrap_or(0) + 1;
+    format!("{}-{:02}-{}T{}Z", year, month, day, time)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn entry() -> AccessEntry {
+        // Tue, 10 Oct 2000 13:55:36 GMT
+        let received = UNIX_EPOCH + Duration::from_secs(971186136);
+
+        AccessEntry {
+            time: rfc3339_time(received),
+            request_id: String::from("request-1"),
+            client: Some(String::from("127.0.0.1")),
+            method: String::from("GET"),
+            path: String::from("/about?\"quoted\""),
+            version: String::from("HTTP/1.1"),
+            route: Some(String::from("about")),
+            status: 200,
+            bytes: Some(2326),
+            duration_ms: 1.5,
+            referer: None,
+            user_agent: Some(String::from("curl/8.0")),
+            received,
+        }
+    }
+
+    #[test]
+    fn common_format() {
+        assert_eq!(
+            entry().format(AccessLogFormat::Common),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /about?\\\"quoted\\\" HTTP/1.1\" 200 2326"
+        );
+    }
+
+    #[test]
+    fn combined_format_adds_referer_and_user_agent() {
+        assert_eq!(
+            entry().format(AccessLogFormat::Combined),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /about?\\\"quoted\\\" HTTP/1.1\" 200 2326 \"-\" \"curl/8.0\""
+        );
+    }
+
+    #[test]
+    fn missing_values_are_dashes() {
+        let mut entry = entry();
+        entry.client = None;
+        entry.bytes = None;
+
+        assert!(entry.format(AccessLogFormat::Common).starts_with("- - - ["));
+        assert!(entry.format(AccessLogFormat::Common).ends_with(" 200 -"));
+    }
+
+    #[test]
+    fn json_format_has_every_field() {
+        let json: serde_json::Value =
+            serde_json::from_str(&entry().format(AccessLogFormat::Json)).unwrap();
+
+        assert_eq!(json["time"], "2000-10-10T13:55:36Z");
+        assert_eq!(json["request_id"], "request-1");
+        assert_eq!(json["route"], "about");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["referer"], serde_json::Value::Null);
+        assert!(json.get("received").is_none());
+    }
+}
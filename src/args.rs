@@ -2,6 +2,15 @@ use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
 
+use garnish_web_server::artifact::ARTIFACT_PATH_DEFAULT;
+use garnish_web_server::access::AccessLogFormat;
+use garnish_web_server::config::{
+    parse_define, parse_header, parse_rewrite, ProjectConfig, RewriteRule, TlsConfig,
+};
+use garnish_web_server::mount::parse_mount;
+use garnish_web_server::output::OutputMode;
+use garnish_web_server::vhost::parse_host;
+
 pub const WEB_GARNISH_SERVE_PATH: &str = "WEB_GARNISH_SERVE_PATH";
 
 #[derive(Debug, Parser)]
@@ -20,15 +29,308 @@ pub struct ServerArgs {
 
     /// Where to write output. If not provided output will go to stdout.
     #[arg(long, verbatim_doc_comment)]
-    pub output_path: Option<PathBuf>
+    pub output_path: Option<PathBuf>,
+
+    /// Config value available to scripts, in form 'key=value'. May be repeated.
+    /// Takes priority over values from 'garnish-web.toml' and environment variables.
+    #[arg(long = "define", value_parser = parse_define, verbatim_doc_comment)]
+    pub defines: Vec<(String, String)>,
+
+    /// Glob pattern, relative to the serve path, of files to compile. May be repeated.
+    /// Replaces include patterns from the config file. Default is '**/*.garnish'.
+    #[arg(long = "include", verbatim_doc_comment)]
+    pub includes: Vec<String>,
+
+    /// Pattern, in .gitignore syntax, of files to skip. May be repeated.
+    /// Added after exclude patterns from the config file, so '!pattern' can re-include files.
+    #[arg(long = "exclude", verbatim_doc_comment)]
+    pub excludes: Vec<String>,
+
+    /// Config file to load instead of 'garnish-web.toml' in the serve path.
+    #[arg(long, verbatim_doc_comment)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on. Default is 0.0.0.0:3000.
+    #[arg(long, verbatim_doc_comment)]
+    pub bind: Option<String>,
+
+    /// Directory of files served as is when no route matches. May be repeated.
+    /// Replaces static directories from the config file.
+    #[arg(long = "static-dir", verbatim_doc_comment)]
+    pub static_dirs: Vec<PathBuf>,
+
+    /// Header added to every response, in form 'Name: value'. May be repeated.
+    #[arg(long = "header", value_parser = parse_header, verbatim_doc_comment)]
+    pub headers: Vec<(String, String)>,
+
+    /// Largest request body, in bytes, that will be accepted.
+    #[arg(long, verbatim_doc_comment)]
+    pub max_body_bytes: Option<u64>,
+
+    /// Seconds a route may execute before the request fails.
+    #[arg(long, verbatim_doc_comment)]
+    pub request_timeout_secs: Option<u64>,
+
+    /// Largest size, in bytes, of a request's headers.
+    #[arg(long, verbatim_doc_comment)]
+    pub max_header_bytes: Option<u32>,
+
+    /// Seconds to wait for a request's headers before closing the connection.
+    #[arg(long, verbatim_doc_comment)]
+    pub read_timeout_secs: Option<u64>,
+
+    /// Most connections open at once.
+    #[arg(long, verbatim_doc_comment)]
+    pub max_connections: Option<usize>,
+
+    /// Seconds to wait for in-flight requests when stopping. Default is 30.
+    #[arg(long, verbatim_doc_comment)]
+    pub drain_timeout_secs: Option<u64>,
+
+    /// Only accept HTTP/1 connections.
+    #[arg(long, verbatim_doc_comment)]
+    pub no_http2: bool,
+
+    /// Serve the '/_health', '/_ready' and '/_info' endpoints.
+    #[arg(long, verbatim_doc_comment)]
+    pub probes: bool,
+
+    /// Serve Prometheus metrics at '/metrics'.
+    #[arg(long, verbatim_doc_comment)]
+    pub metrics: bool,
+
+    /// Address to serve metrics on, instead of alongside the site. Implies --metrics.
+    #[arg(long, verbatim_doc_comment)]
+    pub metrics_bind: Option<String>,
+
+    /// One of off, error, warn, info, debug or trace.
+    #[arg(long, verbatim_doc_comment)]
+    pub log_level: Option<String>,
+
+    /// Path rewrite in form 'from=to', applied before matching routes. May be repeated.
+    /// Replaces rewrites from the config file.
+    #[arg(long = "rewrite", value_parser = parse_rewrite, verbatim_doc_comment)]
+    pub rewrites: Vec<RewriteRule>,
+
+    /// Send responses uncompressed, even to clients that accept compression.
+    #[arg(long, verbatim_doc_comment)]
+    pub no_compression: bool,
+
+    /// Formatting of rendered HTML and CSS. One of compact, minified or pretty. Default is compact.
+    #[arg(long, verbatim_doc_comment)]
+    pub output_mode: Option<OutputMode>,
+
+    /// Log every request in the given format. One of common, combined or json.
+    /// Written to stdout unless --access-log-file is given. Other log messages are written to stderr.
+    #[arg(long, verbatim_doc_comment)]
+    pub access_log: Option<AccessLogFormat>,
+
+    /// File to write the access log to, rotated once it grows past 10 MiB. Implies --access-log.
+    #[arg(long, verbatim_doc_comment)]
+    pub access_log_file: Option<PathBuf>,
+}
+
+impl ServerArgs {
+    /// Replaces config file values with any provided flags.
+    pub fn apply_overrides(&self, config: &mut ProjectConfig) {
+        let server = &mut config.server;
+
+        if let Some(bind) = &self.bind {
+            server.bind = bind.clone();
+        }
+
+        if !self.includes.is_empty() {
+            server.include = self.includes.clone();
+        }
+
+        server.exclude.extend(self.excludes.iter().cloned());
+
+        if !self.static_dirs.is_empty() {
+            server.static_dirs = self.static_dirs.clone();
+        }
+
+        for (name, value) in self.headers.iter() {
+            server.headers.insert(name.clone(), value.clone());
+        }
+
+        if let Some(max) = self.max_body_bytes {
+            server.limits.max_body_bytes = Some(max);
+        }
+
+        if let Some(secs) = self.request_timeout_secs {
+            server.limits.request_timeout_secs = Some(secs);
+        }
+
+        if let Some(max) = self.max_header_bytes {
+            server.limits.max_header_bytes = Some(max);
+        }
+
+        if let Some(secs) = self.read_timeout_secs {
+            server.limits.read_timeout_secs = Some(secs);
+        }
+
+        if let Some(max) = self.max_connections {
+            server.limits.max_connections = Some(max);
+        }
+
+        if let Some(secs) = self.drain_timeout_secs {
+            server.limits.drain_timeout_secs = Some(secs);
+        }
+
+        if self.no_http2 {
+            server.http.http2 = false;
+        }
+
+        if self.probes {
+            server.probes.enabled = true;
+        }
+
+        if self.metrics {
+            server.metrics.enabled = true;
+        }
+
+        if let Some(bind) = &self.metrics_bind {
+            server.metrics.enabled = true;
+            server.metrics.bind = Some(bind.clone());
+        }
+
+        if let Some(level) = &self.log_level {
+            server.logging.level = Some(level.clone());
+        }
+
+        if !self.rewrites.is_empty() {
+            server.rewrites = self.rewrites.clone();
+        }
+
+        if self.no_compression {
+            server.compression.enabled = false;
+        }
+
+        if let Some(mode) = self.output_mode {
+            server.output.mode = mode;
+        }
+
+        if let Some(format) = self.access_log {
+            server.logging.access.enabled = true;
+            server.logging.access.format = format;
+        }
+
+        if let Some(file) = &self.access_log_file {
+            server.logging.access.enabled = true;
+            server.logging.access.file = Some(file.clone());
+        }
+
+        if let ServerSubCommand::Serve {
+            tls_cert,
+            tls_key,
+            tls_redirect_bind,
+            ..
+        } = &self.command
+        {
+            if let (Some(cert), Some(key)) = (tls_cert, tls_key) {
+                server.tls = Some(TlsConfig::new(cert, key));
+            }
+
+            if let (Some(tls), Some(bind)) = (&mut server.tls, tls_redirect_bind) {
+                tls.redirect_bind = Some(bind.clone());
+            }
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
 pub enum ServerSubCommand {
-    #[command()]
-    Serve,
+    /// Starts the web server.
+    /// With one or more mounts or virtual hosts, only their directories are served. Use '/=path' to serve a directory at the root.
+    #[command(verbatim_doc_comment)]
+    Serve {
+        /// Directory to compile as a separate site, served under a URL prefix, in form '/prefix=path'. May be repeated.
+        /// Requests go to the mount with the longest matching prefix.
+        #[arg(long = "mount", value_parser = parse_mount, verbatim_doc_comment)]
+        mounts: Vec<(String, PathBuf)>,
+
+        /// Directory to compile as a separate site, served for requests with a matching Host header, in form 'host=path'. May be repeated.
+        /// Host may be an exact name, '*.domain' for any subdomain or '*' for hosts nothing else matches.
+        /// Use 'host/prefix=path' to serve the directory under a URL prefix of that host.
+        /// Requests for unknown hosts receive 421 Misdirected Request. Mounts given along with virtual hosts are served for '*'.
+        #[arg(long = "vhost", value_parser = parse_host, verbatim_doc_comment)]
+        hosts: Vec<(String, String, PathBuf)>,
+
+        /// PEM certificate file. Requests are served over HTTPS when given along with a key.
+        /// For local testing, a self-signed certificate can be created with
+        /// 'openssl req -x509 -newkey rsa:2048 -nodes -days 30 -subj "/CN=localhost" -keyout key.pem -out cert.pem'
+        #[arg(long, requires = "tls_key", verbatim_doc_comment)]
+        tls_cert: Option<PathBuf>,
+
+        /// PEM private key file for the certificate.
+        #[arg(long, requires = "tls_cert", verbatim_doc_comment)]
+        tls_key: Option<PathBuf>,
+
+        /// Address of a plain HTTP listener redirecting every request to HTTPS, like '0.0.0.0:80'.
+        #[arg(long, verbatim_doc_comment)]
+        tls_redirect_bind: Option<String>,
+
+        /// Recompile changed files without restarting. Only changed files are rebuilt.
+        /// Can't be combined with mounts or virtual hosts. Requests may choose the output mode with '?output=pretty'.
+        #[arg(long, conflicts_with_all = ["mounts", "hosts"], verbatim_doc_comment)]
+        watch: bool,
+
+        /// Milliseconds between checks for changed files when watching.
+        #[arg(long, default_value_t = 500)]
+        watch_interval_ms: u64,
+
+        /// Serve a site compiled with the 'compile' command instead of compiling the serve path.
+        /// The serve path is still used for the config file and static directories.
+        #[arg(long, conflicts_with_all = ["mounts", "hosts", "watch"], verbatim_doc_comment)]
+        artifact: Option<PathBuf>,
+    },
+
+    /// Compiles the site into a single artifact file that 'serve --artifact' can load without the source files.
+    /// Artifacts can only be loaded by the same server version that wrote them.
+    #[command(verbatim_doc_comment)]
+    Compile {
+        /// File to write the artifact to.
+        #[arg(long, default_value = ARTIFACT_PATH_DEFAULT)]
+        artifact_path: PathBuf,
+    },
+
+    /// Prints the config that results from merging the config file, environment and flags.
+    #[command(verbatim_doc_comment)]
+    Config,
 
     /// Builds expression and writes build data to output.
     #[command()]
     Dump,
+
+    /// Executes every @Test annotation and reports results per file.
+    /// Exits with an error if any test fails.
+    #[command(verbatim_doc_comment)]
+    Test,
+
+    /// Renders every route and compares the output with stored snapshots.
+    /// Additional requests for a route can be listed in a '<file>.fixtures.json' file next to its garnish file.
+    #[command(verbatim_doc_comment)]
+    Snapshot {
+        /// Directory snapshots are read from and written to.
+        #[arg(long, default_value = "snapshots")]
+        snapshot_path: PathBuf,
+
+        /// Rewrite snapshots with the current output instead of failing on mismatch.
+        #[arg(long)]
+        update: bool,
+    },
+
+    /// Reads garnish expressions from stdin and executes them with the site's definitions available.
+    /// End a line with '\' to continue an expression on the next line.
+    #[command(verbatim_doc_comment)]
+    Repl,
+
+    /// Starts a Debug Adapter Protocol server for debugging routes from an editor.
+    /// Communicates over stdio unless a port is provided.
+    #[command(verbatim_doc_comment)]
+    Dap {
+        /// Local TCP port to accept a single debug client on.
+        #[arg(long)]
+        port: Option<u16>,
+    },
 }
\ No newline at end of file
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use garnish_lang::compiler::{build::InstructionMetadata, lex::LexerToken, parse::ParseResult};
+use garnish_lang::simple::{SimpleGarnishData, SimpleGarnishRuntime};
+use garnish_lang::GarnishRuntime;
+use garnish_lang_utilities::BuildMetadata;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::context::WebContext;
+use crate::prelude::prelude_natives;
+use crate::testing::TestInfo;
+use crate::{RouteInfo, SERVER_VERSION};
+
+pub const ARTIFACT_PATH_DEFAULT: &str = "site.garnish-artifact";
+
+/// Incremented whenever the artifact layout changes.
+pub const ARTIFACT_FORMAT_VERSION: u32 = 1;
+
+/// Version information read before the rest of the artifact, so incompatible artifacts fail with a clear message.
+#[derive(Debug, Clone, Deserialize)]
+struct ArtifactHeader {
+    server_version: String,
+    format_version: u32,
+}
+
+/// Compiled site that can be served without its source files.
+#[derive(Serialize, Deserialize)]
+struct SiteArtifact {
+    server_version: String,
+    format_version: u32,
+    data: SimpleGarnishData,
+    expressions: HashMap<String, usize>,
+    route_mapping: HashMap<String, RouteInfo>,
+    tests: Vec<TestInfo>,
+    metadata: Vec<ArtifactMetadata>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArtifactMetadata {
+    name: String,
+    source: String,
+    root_index: usize,
+    tokens: Vec<LexerToken>,
+    parse_result: ParseResult,
+    instruction_data: Vec<InstructionMetadata>,
+}
+
+/// Writes the compiled site to a single file.
+pub fn save_artifact(
+    path: &Path,
+    route_mapping: &HashMap<String, RouteInfo>,
+    runtime: &SimpleGarnishRuntime<SimpleGarnishData>,
+    context: &WebContext,
+) -> Result<(), String> {
+    let metadata = context
+        .metadata()
+        .iter()
+        .filter_map(|m| {
+            // metadata is only missing parts when its build failed, in which case it isn't needed
+            Some(ArtifactMetadata {
+                name: m.get_name().clone(),
+                source: m.get_input().clone(),
+                root_index: m.get_root_index(),
+                tokens: m.get_lexing_tokens()?.clone(),
+                parse_result: m.get_parse_result()?.clone(),
+                instruction_data: m.get_instruction_data()?.clone(),
+            })
+        })
+        .collect();
+
+    let artifact = SiteArtifact {
+        server_version: SERVER_VERSION.to_string(),
+        format_version: ARTIFACT_FORMAT_VERSION,
+        data: runtime.get_data().clone(),
+        expressions: context.expressions().clone(),
+        route_mapping: route_mapping.clone(),
+        tests: context.tests().clone(),
+        metadata,
+    };
+
+    let text = serde_json::to_string(&artifact).or_else(|e| Err(e.to_string()))?;
+
+    fs::write(path, text)
+        .or_else(|e| Err(format!("Failed to write artifact {:?}. Reason: {}", path, e)))?;
+
+    info!(
+        "Wrote artifact with {} routes to {:?}",
+        route_mapping.len(),
+        path
+    );
+
+    Ok(())
+}
+
+/// Reads a compiled site. Fails if the artifact was written by a different server version.
+/// Built-in natives are registered, other natives and config values need to be added to the returned context.
+pub fn load_artifact(
+    path: &Path,
+) -> Result<
+    (
+        HashMap<String, RouteInfo>,
+        SimpleGarnishRuntime<SimpleGarnishData>,
+        WebContext,
+    ),
+    String,
+> {
+    debug!("Loading artifact {:?}", path);
+
+    let text = fs::read_to_string(path)
+        .or_else(|e| Err(format!("Failed to read artifact {:?}. Reason: {}", path, e)))?;
+
+    let header: ArtifactHeader = serde_json::from_str(&text)
+        .or_else(|e| Err(format!("{:?} is not a site artifact. Reason: {}", path, e)))?;
+
+    if header.server_version != SERVER_VERSION || header.format_version != ARTIFACT_FORMAT_VERSION {
+        return Err(format!(
+            "Artifact {:?} was built by server version {} (format {}). This server is version {} (format {}). Compile the site again.",
+            path, header.server_version, header.format_version, SERVER_VERSION, ARTIFACT_FORMAT_VERSION
+        ));
+    }
+
+    let artifact: SiteArtifact = serde_json::from_str(&text)
+        .or_else(|e| Err(format!("Failed to parse artifact {:?}. Reason: {}", path, e)))?;
+
+    let mut context = WebContext::new();
+
+    for (name, index) in artifact.expressions {
+        context.insert_expression(name, index);
+    }
+
+    for test in artifact.tests {
+        context.insert_test(test);
+    }
+
+    for m in artifact.metadata {
+        context.metadata_mut().push(BuildMetadata::new(
+            m.name,
+            m.source,
+            m.root_index,
+            m.tokens,
+            m.parse_result,
+            m.instruction_data,
+        ));
+    }
+
+    for native in prelude_natives() {
+        context.insert_native(native);
+    }
+
+    info!(
+        "Loaded artifact with {} routes from {:?}",
+        artifact.route_mapping.len(),
+        path
+    );
+
+    Ok((
+        artifact.route_mapping,
+        SimpleGarnishRuntime::new(artifact.data),
+        context,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use garnish_lang::GarnishData;
+
+    use super::*;
+    use crate::create_runtime_from_sources;
+
+    fn artifact_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "garnish-web-server-artifact-{}-{}",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn loaded_artifacts_match_the_saved_site() {
+        let path = artifact_path("round-trip");
+        let (route_mapping, runtime, mut context) = create_runtime_from_sources(
+            vec![(PathBuf::from("/site/index.garnish"), String::from("5"))],
+            "/site",
+        )
+        .unwrap();
+        context.insert_test(TestInfo::new(PathBuf::from("/site/index.garnish"), "five", 0));
+
+        save_artifact(&path, &route_mapping, &runtime, &context).unwrap();
+        let (loaded_mapping, loaded_runtime, loaded_context) = load_artifact(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(loaded_mapping, route_mapping);
+        assert_eq!(
+            loaded_runtime.get_data().get_data_len(),
+            runtime.get_data().get_data_len()
+        );
+        assert_eq!(
+            loaded_runtime.get_data().get_jump_table_len(),
+            runtime.get_data().get_jump_table_len()
+        );
+        assert_eq!(loaded_context.expressions(), context.expressions());
+        assert_eq!(loaded_context.tests(), context.tests());
+        assert_eq!(loaded_context.natives().len(), prelude_natives().len());
+    }
+
+    #[test]
+    fn artifacts_from_other_versions_are_rejected() {
+        let path = artifact_path("other-version");
+        fs::write(
+            &path,
+            format!(
+                "{{\"server_version\": \"0.0.0\", \"format_version\": {}}}",
+                ARTIFACT_FORMAT_VERSION
+            ),
+        )
+        .unwrap();
+
+        let result = load_artifact(&path);
+        let _ = fs::remove_file(&path);
+
+        match result {
+            Ok(_) => panic!("artifact from another server version was loaded"),
+            Err(e) => assert!(e.contains("server version 0.0.0")),
+        }
+    }
+
+    #[test]
+    fn other_files_are_not_artifacts() {
+        let path = artifact_path("not-artifact");
+        fs::write(&path, "5").unwrap();
+
+        let result = load_artifact(&path);
+        let _ = fs::remove_file(&path);
+
+        match result {
+            Ok(_) => panic!("file that isn't an artifact was loaded"),
+            Err(e) => assert!(e.contains("is not a site artifact")),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::Router;
+use log::debug;
+
+use crate::config::{ProjectConfig, ScriptValue, ServerConfig};
+use crate::incremental::SiteCompiler;
+use crate::native::NativeFunction;
+use crate::{create_router, find_source_files, read_sources, SharedState};
+
+/// Compiles garnish sources from a serve path and/or memory into a router that can be mounted in another application.
+#[derive(Clone, Debug, Default)]
+pub struct WebServerBuilder {
+    serve_path: Option<PathBuf>,
+    sources: Vec<(PathBuf, String)>,
+    natives: Vec<NativeFunction>,
+    values: Vec<(String, ScriptValue)>,
+    settings: Option<ServerConfig>,
+    allow_build_errors: bool,
+}
+
+impl WebServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Directory to compile every garnish file from.
+    pub fn serve_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.serve_path = Some(path.into());
+        self
+    }
+
+    /// Adds an in memory file. The path is relative to the serve path, if one is set, and determines the route.
+    pub fn source<P: Into<PathBuf>, T: Into<String>>(mut self, path: P, text: T) -> Self {
+        self.sources.push((path.into(), text.into()));
+        self
+    }
+
+    /// Registers a Rust function callable by name from every page.
+    /// Building fails if a definition, including the prelude's, has the same name, since the definition would be used instead.
+    pub fn native(mut self, native: NativeFunction) -> Self {
+        self.natives.push(native);
+        self
+    }
+
+    /// Sets a config value available to scripts. Takes priority over values loaded from the serve path.
+    pub fn value<T: Into<String>>(mut self, key: T, value: ScriptValue) -> Self {
+        self.values.push((key.into(), value));
+        self
+    }
+
+    /// Replaces the server settings loaded from the serve path's config file.
+    pub fn settings(mut self, settings: ServerConfig) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Leaves files that fail to compile out of the site instead of failing the build.
+    /// Their errors are kept on the state, where the ready probe reports them.
+    pub fn allow_build_errors(mut self) -> Self {
+        self.allow_build_errors = true;
+        self
+    }
+
+    /// Compiles all sources into the state shared by [`crate::serve_request`].
+    pub fn build(self) -> Result<Arc<SharedState>, String> {
+        let base_path = self.serve_path.unwrap_or_default();
+
+        let base_path_str = match base_path.to_str() {
+            None => Err(format!(
+                "Could not covert serve path to string. Path: {:?}",
+                base_path
+            ))?,
+            Some(s) => s.to_string(),
+        };
+
+        let mut config = match base_path_str.is_empty() {
+            true => ProjectConfig::default(),
+            false => ProjectConfig::load(&base_path)?,
+        };
+
+        if let Some(settings) = self.settings {
+            config.server = settings;
+        }
+
+        let mut sources = vec![];
+
+        if !base_path_str.is_empty() {
+            debug!("Loading sources from path: {}", base_path_str);
+
+            let paths =
+                find_source_files(&base_path, &config.server.include, &config.server.exclude)?;
+            sources.append(&mut read_sources(paths)?);
+        }
+
+        for (path, text) in self.sources {
+            sources.push((base_path.join(path), text));
+        }
+
+        let compiler = match self.allow_build_errors {
+            true => SiteCompiler::from_sources_allowing_errors(sources, base_path_str.as_str())?,
+            false => SiteCompiler::from_sources(sources, base_path_str.as_str())?,
+        };
+        let build_errors = compiler.build_errors();
+        let (route_mapping, runtime, mut context) = compiler.into_parts();
+
+        context.set_values(config.script.resolve_values(&vec![]));
+        for (key, value) in self.values {
+            context.insert_value(key, value);
+        }
+
+        for native in self.natives {
+            if context.expression(native.name()).is_some() {
+                Err(format!(
+                    "Native function \"{}\" has the same name as a definition, which would be called instead. Rename one of them.",
+                    native.name()
+                ))?;
+            }
+
+            debug!("Registering native function: {}", native.name());
+            context.insert_native(native);
+        }
+
+        Ok(Arc::new(
+            SharedState::new(route_mapping, runtime, context)
+                .with_settings(config.server.with_base_path(&base_path))
+                .with_build_errors(build_errors),
+        ))
+    }
+
+    /// Compiles all sources and creates a router serving them.
+    pub fn router(self) -> Result<Router, String> {
+        self.build().map(create_router)
+    }
+}
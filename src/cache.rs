@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::body::Body;
+use axum::http::header::{IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use axum::http::{Method, Request};
+use log::debug;
+
+use crate::config::{CacheConfig, CacheRule};
+
+/// Content encodings that [`encoded_etag`] can add to a tag.
+const TAG_ENCODINGS: [&str; 4] = ["gzip", "br", "zstd", "deflate"];
+
+/// Rendered output of a route, kept until it expires.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    body: String,
+    etag: String,
+    modified: SystemTime,
+    created: Instant,
+    expires: Instant,
+}
+
+impl CachedResponse {
+    pub fn new<T: Into<String>>(body: T, ttl: Duration) -> Self {
+        let body = body.into();
+        let created = Instant::now();
+
+        Self {
+            etag: etag(&body),
+            body,
+            modified: SystemTime::now(),
+            created,
+            expires: created + ttl,
+        }
+    }
+
+    pub fn body(&self) -> &String {
+        &self.body
+    }
+
+    pub fn etag(&self) -> &String {
+        &self.etag
+    }
+
+    /// When the response was rendered.
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires
+    }
+
+    /// Time left before the response expires.
+    pub fn remaining(&self) -> Duration {
+        self.expires.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Responses of cached routes for one compiled site.
+/// Each build gets its own cache, so replacing a site's state discards everything cached for the previous build.
+#[derive(Debug)]
+pub struct ResponseCache {
+    entries: Mutex<HashMap<String, CachedResponse>>,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new(&CacheConfig::default())
+    }
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_entries: config.max_entries,
+            max_bytes: config.max_bytes,
+        }
+    }
+
+    /// Returns the response for the key if it hasn't expired.
+    pub fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.get(key) {
+            Some(response) if response.is_expired() => {
+                debug!("Cached response for \"{}\" expired", key);
+                entries.remove(key);
+                None
+            }
+            response => response.cloned(),
+        }
+    }
+
+    /// Keeps the response, removing expired and then the oldest responses to stay within the limits.
+    pub fn insert<T: Into<String>>(&self, key: T, response: CachedResponse) {
+        let key = key.into();
+
+        if self.max_entries == 0 || response.body.len() > self.max_bytes {
+            debug!("Response for \"{}\" is larger than the cache. Not caching.", key);
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        entries.remove(&key);
+        entries.retain(|_, r| !r.is_expired());
+
+        let mut bytes = entries.values().map(|r| r.body.len()).sum::<usize>();
+
+        while entries.len() >= self.max_entries || bytes + response.body.len() > self.max_bytes {
+            let oldest = match entries.iter().min_by_key(|(_, r)| r.created) {
+                None => break,
+                Some((k, _)) => k.clone(),
+            };
+
+            if let Some(removed) = entries.remove(&oldest) {
+                debug!("Removing cached response for \"{}\" to make room", oldest);
+                bytes -= removed.body.len();
+            }
+        }
+
+        entries.insert(key, response);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// Key identifying a response of the route, including the parts of the request the rule varies by.
+pub fn cache_key(rule: &CacheRule, route: &str, request: &Request<Body>) -> String {
+    let mut key = route.to_string();
+
+    if rule.vary_query {
+        key.push('?');
+        key.push_str(request.uri().query().unwrap_or_default());
+    }
+
+    for name in rule.vary_headers.iter() {
+        let values = request
+            .headers()
+            .get_all(name.as_str())
+            .iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+
+        key.push_str(&format!("\n{}: {}", name.to_lowercase(), values));
+    }
+
+    key
+}
+
+/// Vary header listing the request headers the rule keeps separate responses for.
+/// The query is part of the URL, so it doesn't need to be listed.
+pub fn vary_header(rule: &CacheRule) -> Option<String> {
+    match rule.vary_headers.is_empty() {
+        true => None,
+        false => Some(
+            rule.vary_headers
+                .iter()
+                .map(|name| name.to_lowercase())
+                .collect::<Vec<String>>()
+                .join(", "),
+        ),
+    }
+}
+
+/// Strong entity tag for a response body.
+/// Uses 64 bit FNV-1a so tags stay the same across builds and between servers of the same site.
+pub fn etag(body: &str) -> String {
+    let hash = body.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    format!("\"{:x}-{:016x}\"", body.len(), hash)
+}
+
+/// Whether the request's validators match the response.
+/// For GET and HEAD this means the client already has it, for other methods the precondition failed.
+/// If-Modified-Since is only checked for GET and HEAD when there is no If-None-Match header.
+pub fn is_not_modified(
+    request: &Request<Body>,
+    etag: &str,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    let headers = request.headers();
+
+    if headers.contains_key(IF_NONE_MATCH) {
+        return matches_none_match(request, Some(etag));
+    }
+
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return false;
+    }
+
+    match (
+        last_modified,
+        headers
+            .get(IF_MODIFIED_SINCE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok()),
+    ) {
+        // http dates only have second precision
+        (Some(modified), Some(since)) => httpdate::HttpDate::from(modified) <= httpdate::HttpDate::from(since),
+        _ => false,
+    }
+}
+
+/// Whether the request's If-None-Match header lists the tag, or `*` which matches any current response.
+/// Without a tag only `*` can match.
+pub fn matches_none_match(request: &Request<Body>, etag: Option<&str>) -> bool {
+    // weak comparison, as required for If-None-Match
+    request
+        .headers()
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim())
+        .map(|tag| identity_etag(tag.trim_start_matches("W/")))
+        .any(|tag| tag == "*" || Some(tag.as_str()) == etag)
+}
+
+/// Entity tag of a response whose body is sent with a content encoding.
+/// The encoded body is a different representation, so it needs a different tag.
+pub fn encoded_etag(etag: &str, encoding: &str) -> String {
+    match etag.strip_suffix('"') {
+        Some(tag) => format!("{}-{}\"", tag, encoding),
+        None => etag.to_string(),
+    }
+}
+
+/// Tag of the unencoded response, removing the encoding added by [`encoded_etag`].
+/// Validators are checked before compression, so a client that has the encoded response still matches.
+pub fn identity_etag(etag: &str) -> String {
+    TAG_ENCODINGS
+        .iter()
+        .find_map(|encoding| etag.strip_suffix(&format!("-{}\"", encoding)))
+        .map(|tag| format!("{}\"", tag))
+        .unwrap_or_else(|| etag.to_string())
+}
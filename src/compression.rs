@@ -0,0 +1,163 @@
+use std::path::{Path, PathBuf};
+
+use axum::body::Body;
+use axum::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use axum::http::{Extensions, HeaderMap, HeaderValue, Request, StatusCode, Version};
+use axum::middleware::{from_fn, Next};
+use axum::response::Response;
+use axum::Router;
+use tower_http::compression::predicate::{Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+
+use crate::cache::{encoded_etag, identity_etag};
+use crate::config::CompressionConfig;
+
+/// Content encodings of precompressed sidecar files and their extensions, most preferred first.
+const PRECOMPRESSED_ENCODINGS: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// Layer compressing responses with the configured encodings, for clients that accept them.
+/// Responses that already have a Content-Encoding, like precompressed static files, are left as is.
+pub fn compression_layer(config: &CompressionConfig) -> CompressionLayer<impl Predicate> {
+    let settings = config.clone();
+    let compressible = move |_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions| {
+        headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|t| settings.compresses_content_type(t))
+            .unwrap_or(false)
+    };
+
+    CompressionLayer::new()
+        .gzip(config.enabled && config.gzip)
+        .br(config.enabled && config.br)
+        .zstd(config.enabled && config.zstd)
+        .deflate(false)
+        .compress_when(SizeAbove::new(config.min_bytes).and(compressible))
+}
+
+/// Adds response compression to the app, when enabled.
+/// Compressed responses get the encoding added to their entity tag, see [`encoded_etag`].
+pub fn with_compression(app: Router, config: &CompressionConfig) -> Router {
+    match config.enabled {
+        true => app
+            .layer(compression_layer(config))
+            .layer(from_fn(encoded_etag_middleware)),
+        false => app,
+    }
+}
+
+/// Gives responses compressed by the inner layer their own entity tag.
+/// Validators are checked on the unencoded response, so a 304 response gets back the tag the client sent.
+async fn encoded_etag_middleware(request: Request<Body>, next: Next<Body>) -> Response {
+    let requested = request
+        .headers()
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim().to_string())
+        .collect::<Vec<String>>();
+
+    let mut response = next.run(request).await;
+
+    let etag = match response.headers().get(ETAG).and_then(|v| v.to_str().ok()) {
+        None => return response,
+        Some(etag) => etag.to_string(),
+    };
+
+    let encoding = response
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+
+    let tag = match (response.status(), encoding) {
+        (StatusCode::NOT_MODIFIED, _) => requested
+            .into_iter()
+            .find(|tag| identity_etag(tag.trim_start_matches("W/")) == etag),
+        (_, Some(encoding)) => Some(encoded_etag(&etag, &encoding)),
+        _ => None,
+    };
+
+    if let Some(tag) = tag.and_then(|t| HeaderValue::from_str(&t).ok()) {
+        response.headers_mut().insert(ETAG, tag);
+    }
+
+    response
+}
+
+/// Quality the request's Accept-Encoding header gives the encoding, 0 when it isn't accepted.
+/// An entry naming the encoding takes priority over '*'.
+pub fn encoding_quality(request: &Request<Body>, encoding: &str) -> f32 {
+    let mut named = None;
+    let mut any = None;
+
+    for (name, quality) in request
+        .headers()
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let name = parts.next()?.trim();
+            let quality = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .filter_map(|q| q.trim().parse::<f32>().ok())
+                .next()
+                .unwrap_or(1.0);
+
+            Some((name, quality))
+        })
+    {
+        if name.eq_ignore_ascii_case(encoding) {
+            named = Some(quality);
+        } else if name == "*" {
+            any = Some(quality);
+        }
+    }
+
+    named.or(any).unwrap_or(0.0)
+}
+
+/// Whether the request's Accept-Encoding header allows the encoding.
+pub fn accepts_encoding(request: &Request<Body>, encoding: &str) -> bool {
+    encoding_quality(request, encoding) > 0.0
+}
+
+/// Finds a precompressed copy of the file in an encoding that is enabled and accepted by the request,
+/// returning its path and content encoding. The encoding with the highest quality is used when several are present.
+pub fn find_precompressed(
+    path: &Path,
+    request: &Request<Body>,
+    config: &CompressionConfig,
+) -> Option<(PathBuf, &'static str)> {
+    if !config.enabled {
+        return None;
+    }
+
+    let mut candidates = PRECOMPRESSED_ENCODINGS
+        .iter()
+        .filter(|(encoding, _)| match *encoding {
+            "br" => config.br,
+            "gzip" => config.gzip,
+            _ => false,
+        })
+        .map(|(encoding, extension)| (*encoding, *extension, encoding_quality(request, encoding)))
+        .filter(|(_, _, quality)| *quality > 0.0)
+        .collect::<Vec<_>>();
+
+    // stable, so equal qualities keep the preferred order
+    candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+    candidates
+        .into_iter()
+        .map(|(encoding, extension, _)| {
+            let mut sidecar = path.as_os_str().to_owned();
+            sidecar.push(".");
+            sidecar.push(extension);
+
+            (PathBuf::from(sidecar), encoding)
+        })
+        .find(|(sidecar, _)| sidecar.is_file())
+}
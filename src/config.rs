@@ -0,0 +1,656 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use garnish_lang::simple::{DataError, SimpleGarnishData, SimpleNumber};
+use garnish_lang::{GarnishData, RuntimeError};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::access::AccessLogFormat;
+use crate::output::OutputMode;
+use crate::prelude::add_string;
+use crate::INCLUDE_PATTERN_DEFAULT;
+
+pub const CONFIG_FILE_NAME: &str = "garnish-web.toml";
+pub const ENV_PREFIX_DEFAULT: &str = "GARNISH_WEB_VALUE_";
+pub const BIND_ADDRESS_DEFAULT: &str = "0.0.0.0:3000";
+
+/// Contents of the optional 'garnish-web.toml' file in the serve path.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProjectConfig {
+    pub server: ServerConfig,
+    pub script: ScriptConfig,
+}
+
+/// Settings for compiling and serving a site. Command line flags take priority over these.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub bind: String,
+    /// Glob patterns, relative to the serve path, of files to compile.
+    pub include: Vec<String>,
+    /// Patterns, in .gitignore syntax, of files to skip even if included.
+    /// Applied after the default patterns and the '.garnishignore' file, so '!pattern' can re-include files.
+    pub exclude: Vec<String>,
+    /// Directories whose files are served as is when a request doesn't match a route.
+    pub static_dirs: Vec<PathBuf>,
+    /// Headers added to every response.
+    pub headers: BTreeMap<String, String>,
+    pub limits: LimitsConfig,
+    pub http: HttpConfig,
+    pub logging: LoggingConfig,
+    pub rewrites: Vec<RewriteRule>,
+    pub cache: CacheConfig,
+    pub compression: CompressionConfig,
+    pub output: OutputConfig,
+    /// Serve over HTTPS when set.
+    pub tls: Option<TlsConfig>,
+    pub probes: ProbeConfig,
+    pub metrics: MetricsConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: BIND_ADDRESS_DEFAULT.to_string(),
+            include: vec![INCLUDE_PATTERN_DEFAULT.to_string()],
+            exclude: vec![],
+            static_dirs: vec![],
+            headers: BTreeMap::new(),
+            limits: LimitsConfig::default(),
+            http: HttpConfig::default(),
+            logging: LoggingConfig::default(),
+            rewrites: vec![],
+            cache: CacheConfig::default(),
+            compression: CompressionConfig::default(),
+            output: OutputConfig::default(),
+            tls: None,
+            probes: ProbeConfig::default(),
+            metrics: MetricsConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Requests with a larger Content-Length are rejected.
+    pub max_body_bytes: Option<u64>,
+    /// Longest a route's script may execute before the request fails.
+    pub request_timeout_secs: Option<u64>,
+    /// Largest size, in bytes, of a request's headers. Values below 8192 are raised to 8192 for HTTP/1.
+    pub max_header_bytes: Option<u32>,
+    /// Longest time to wait for a request's headers before closing an HTTP/1 connection.
+    pub read_timeout_secs: Option<u64>,
+    /// Most connections open at once. Further connections wait to be accepted until others close.
+    pub max_connections: Option<usize>,
+    /// Longest time, after being asked to stop, to wait for in-flight requests before closing their connections.
+    /// Default is 30 seconds.
+    pub drain_timeout_secs: Option<u64>,
+}
+
+pub const DRAIN_TIMEOUT_SECS_DEFAULT: u64 = 30;
+
+pub const KEEP_ALIVE_INTERVAL_SECS_DEFAULT: u64 = 20;
+
+/// Protocol settings of the listener.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Accept HTTP/2, negotiated through ALPN over TLS, or with prior knowledge (h2c) over plain connections.
+    pub http2: bool,
+    /// Reuse HTTP/1 connections for several requests.
+    pub keep_alive: bool,
+    /// HTTP/2 connections are pinged at this interval, and closed if a ping isn't answered within it. 0 disables pings.
+    pub keep_alive_interval_secs: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            http2: true,
+            keep_alive: true,
+            keep_alive_interval_secs: KEEP_ALIVE_INTERVAL_SECS_DEFAULT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// One of off, error, warn, info, debug or trace. RUST_LOG is used when not set.
+    pub level: Option<String>,
+    pub access: AccessLogConfig,
+}
+
+pub const ACCESS_LOG_MAX_FILE_BYTES_DEFAULT: u64 = 10 * 1024 * 1024;
+pub const ACCESS_LOG_MAX_FILES_DEFAULT: usize = 5;
+
+/// Access log of every request, written regardless of the log level.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    pub enabled: bool,
+    pub format: AccessLogFormat,
+    /// Written to stdout when not set, apart from other log messages which are written to stderr.
+    pub file: Option<PathBuf>,
+    /// Size the file may reach before it is renamed with a '.1' suffix and a new one is started.
+    pub max_file_bytes: u64,
+    /// Number of renamed files to keep. Older ones are removed.
+    pub max_files: usize,
+    /// Log the first address of the X-Forwarded-For header as the client, for servers behind a proxy.
+    pub trust_forwarded: bool,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            format: AccessLogFormat::default(),
+            file: None,
+            max_file_bytes: ACCESS_LOG_MAX_FILE_BYTES_DEFAULT,
+            max_files: ACCESS_LOG_MAX_FILES_DEFAULT,
+            trust_forwarded: false,
+        }
+    }
+}
+
+/// Changes the path of a request before it is matched to a route.
+/// A trailing '*' in 'from' matches any path starting with the text before it, and the remainder replaces the '*' in 'to'.
+/// For example, from 'blog/*' to 'posts/*' rewrites 'blog/first' to 'posts/first'.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RewriteRule {
+    pub from: String,
+    pub to: String,
+}
+
+impl RewriteRule {
+    pub fn new<T: Into<String>, U: Into<String>>(from: T, to: U) -> Self {
+        Self {
+            from: from.into(),
+            to: to.into(),
+        }
+    }
+
+    /// Returns the rewritten path, if the path matches this rule. Leading and trailing slashes are ignored.
+    pub fn apply(&self, path: &str) -> Option<String> {
+        let path = path.trim_matches('/');
+
+        match self.from.strip_suffix('*') {
+            None => match self.from.trim_matches('/') == path {
+                true => Some(self.to.trim_matches('/').to_string()),
+                false => None,
+            },
+            Some(start) => {
+                let to = self.to.trim_start_matches('/');
+                let to = to.strip_suffix('*').unwrap_or(to);
+
+                path.strip_prefix(start.trim_start_matches('/'))
+                    .map(|rest| format!("{}{}", to, rest).trim_matches('/').to_string())
+            }
+        }
+    }
+}
+
+/// Built-in endpoints for health checks. Their paths take priority over garnish routes.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProbeConfig {
+    pub enabled: bool,
+    /// Responds with 200 while the server is running.
+    pub health_path: String,
+    /// Responds with 200 when the current build compiled without errors, and 503 otherwise.
+    pub ready_path: String,
+    /// Responds with the server version and each site's build time, route count and errors, as JSON.
+    pub info_path: String,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            health_path: String::from("/_health"),
+            ready_path: String::from("/_ready"),
+            info_path: String::from("/_info"),
+        }
+    }
+}
+
+/// Prometheus metrics endpoint.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub path: String,
+    /// Serve metrics on this address instead of alongside the site.
+    pub bind: Option<String>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: String::from("/metrics"),
+            bind: None,
+        }
+    }
+}
+
+pub const TLS_RELOAD_INTERVAL_SECS_DEFAULT: u64 = 10;
+
+/// Certificate and private key, in PEM files, used to serve over HTTPS.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Address of a plain HTTP listener that redirects every request to HTTPS.
+    #[serde(default)]
+    pub redirect_bind: Option<String>,
+    /// How often the files are checked for changes. Changed files are loaded without restarting.
+    #[serde(default = "tls_reload_interval_secs_default")]
+    pub reload_interval_secs: u64,
+}
+
+fn tls_reload_interval_secs_default() -> u64 {
+    TLS_RELOAD_INTERVAL_SECS_DEFAULT
+}
+
+impl TlsConfig {
+    pub fn new<T: Into<PathBuf>, U: Into<PathBuf>>(cert: T, key: U) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+            redirect_bind: None,
+            reload_interval_secs: TLS_RELOAD_INTERVAL_SECS_DEFAULT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// Formatting of rendered HTML and CSS.
+    pub mode: OutputMode,
+    /// Let requests choose the mode with the 'output' query parameter, like '?output=pretty'. Meant for development.
+    pub query_override: bool,
+}
+
+pub const COMPRESSION_MIN_BYTES_DEFAULT: u16 = 1024;
+pub const COMPRESSIBLE_CONTENT_TYPES_DEFAULT: [&str; 5] = [
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "image/svg+xml",
+];
+
+/// Compression of responses, negotiated from the request's Accept-Encoding header.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub gzip: bool,
+    pub br: bool,
+    pub zstd: bool,
+    /// Smaller responses are sent uncompressed.
+    pub min_bytes: u16,
+    /// Content types that are compressed. A trailing '*' matches any type starting with the text before it.
+    pub content_types: Vec<String>,
+    /// Serve a static file's '.br' or '.gz' sidecar, when present and accepted, instead of compressing the file.
+    pub precompressed: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            gzip: true,
+            br: true,
+            zstd: true,
+            min_bytes: COMPRESSION_MIN_BYTES_DEFAULT,
+            content_types: COMPRESSIBLE_CONTENT_TYPES_DEFAULT
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+            precompressed: true,
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn compresses_content_type(&self, content_type: &str) -> bool {
+        // ignore parameters like charset
+        let content_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+
+        self.content_types.iter().any(|t| match t.strip_suffix('*') {
+            None => t.to_lowercase() == content_type,
+            Some(start) => content_type.starts_with(&start.to_lowercase()),
+        })
+    }
+}
+
+pub const CACHE_MAX_ENTRIES_DEFAULT: usize = 1024;
+pub const CACHE_MAX_BYTES_DEFAULT: usize = 16 * 1024 * 1024;
+
+/// Routes whose rendered output is kept and reused instead of executing the route for every request.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Most responses kept at once. The oldest are removed first.
+    pub max_entries: usize,
+    /// Most response body bytes kept at once. Larger responses are never cached.
+    pub max_bytes: usize,
+    pub rules: Vec<CacheRule>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: CACHE_MAX_ENTRIES_DEFAULT,
+            max_bytes: CACHE_MAX_BYTES_DEFAULT,
+            rules: vec![],
+        }
+    }
+}
+
+/// Caches the output of matching routes. A trailing '*' in 'route' matches any route starting with the text before it.
+/// Routes are matched without their http method, so 'css/main' also matches a '@Method GET' route in 'css/main.garnish'.
+/// Files can also opt their routes into caching with a @Cache annotation. A configured rule takes priority over it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CacheRule {
+    pub route: String,
+    /// How long a response is reused. Responses aren't kept when 0, but the Cache-Control policy still applies.
+    pub ttl_secs: u64,
+    /// Cache-Control header sent with the route's responses. Defaults to the route's @CacheControl annotation,
+    /// then 'public, max-age=' the time left to live.
+    #[serde(default)]
+    pub cache_control: Option<String>,
+    /// Cache a separate response for each query string.
+    #[serde(default)]
+    pub vary_query: bool,
+    /// Request headers whose values get a separate response each. They're listed in the response's Vary header.
+    #[serde(default)]
+    pub vary_headers: Vec<String>,
+}
+
+impl CacheRule {
+    pub fn new<T: Into<String>>(route: T, ttl_secs: u64) -> Self {
+        Self {
+            route: route.into(),
+            ttl_secs,
+            cache_control: None,
+            vary_query: false,
+            vary_headers: vec![],
+        }
+    }
+
+    pub fn matches(&self, route: &str) -> bool {
+        let route = route.split_once('@').map(|(_, r)| r).unwrap_or(route);
+        let route = route.trim_matches('/');
+
+        match self.route.strip_suffix('*') {
+            None => self.route.trim_matches('/') == route,
+            Some(start) => route.starts_with(start.trim_start_matches('/')),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Makes relative static directories and certificate files relative to the given path instead of the working directory.
+    pub fn with_base_path(mut self, base_path: &Path) -> Self {
+        self.static_dirs = self
+            .static_dirs
+            .iter()
+            .map(|dir| base_path.join(dir))
+            .collect();
+
+        if let Some(tls) = &mut self.tls {
+            tls.cert = base_path.join(&tls.cert);
+            tls.key = base_path.join(&tls.key);
+        }
+
+        if let Some(file) = &mut self.logging.access.file {
+            *file = base_path.join(&file);
+        }
+
+        self
+    }
+
+    /// Applies the first matching rewrite rule.
+    pub fn rewrite(&self, path: &str) -> Option<String> {
+        self.rewrites.iter().find_map(|r| r.apply(path))
+    }
+
+    /// First cache rule matching the route, if any.
+    pub fn cache_rule(&self, route: &str) -> Option<&CacheRule> {
+        self.cache.rules.iter().find(|r| r.matches(route))
+    }
+}
+
+/// Values made available to scripts through the 'config' symbol.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScriptConfig {
+    /// Prefix environment variables need to be considered. The prefix is removed and the rest lowercased to make the key.
+    pub env_prefix: String,
+    /// Keys allowed to come from environment variables. A trailing '*' matches any key starting with the text before it.
+    pub expose: Vec<String>,
+    pub values: BTreeMap<String, ScriptValue>,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        Self {
+            env_prefix: ENV_PREFIX_DEFAULT.to_string(),
+            expose: vec![],
+            values: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ScriptValue {
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl ProjectConfig {
+    /// Reads the config file from the serve path. Missing file results in the default config.
+    pub fn load(serve_path: &Path) -> Result<Self, String> {
+        let path = serve_path.join(CONFIG_FILE_NAME);
+
+        if !path.is_file() {
+            debug!("No config file found at {:?}", path);
+            return Ok(Self::default());
+        }
+
+        Self::load_file(&path)
+    }
+
+    /// Reads a specific config file. Unlike [`ProjectConfig::load`], a missing file is an error.
+    pub fn load_file(path: &Path) -> Result<Self, String> {
+        debug!("Loading config file {:?}", path);
+        let text = std::fs::read_to_string(path)
+            .or_else(|e| Err(format!("Failed to read {:?}. Reason: {}", path, e)))?;
+
+        toml::from_str(&text)
+            .or_else(|e| Err(format!("Failed to parse {:?}. Reason: {}", path, e)))
+    }
+
+    pub fn to_toml(&self) -> Result<String, String> {
+        toml::to_string_pretty(self).or_else(|e| Err(e.to_string()))
+    }
+}
+
+impl ScriptConfig {
+    /// Merges file values, exposed environment variables and command line defines, in increasing priority.
+    pub fn resolve_values(&self, defines: &Vec<(String, String)>) -> BTreeMap<String, ScriptValue> {
+        let mut values = self.values.clone();
+
+        for (name, value) in std::env::vars() {
+            let key = match name.strip_prefix(self.env_prefix.as_str()) {
+                None => continue,
+                Some(k) => k.to_lowercase(),
+            };
+
+            if self.is_exposed(&key) {
+                debug!("Using environment variable {} for config value {}", name, key);
+                values.insert(key, ScriptValue::Text(value));
+            } else {
+                debug!("Skipping environment variable {}. Key {} is not exposed", name, key);
+            }
+        }
+
+        for (key, value) in defines {
+            values.insert(key.clone(), ScriptValue::Text(value.clone()));
+        }
+
+        values
+    }
+
+    fn is_exposed(&self, key: &str) -> bool {
+        self.expose.iter().any(|pattern| match pattern.strip_suffix('*') {
+            None => pattern == key,
+            Some(start) => key.starts_with(start),
+        })
+    }
+}
+
+/// Parses a 'Name: value' command line argument.
+pub fn parse_header(text: &str) -> Result<(String, String), String> {
+    match text.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("Expected header in form 'Name: value'. Found {:?}", text)),
+    }
+}
+
+/// Parses a 'from=to' command line argument.
+pub fn parse_rewrite(text: &str) -> Result<RewriteRule, String> {
+    match text.split_once('=') {
+        Some((from, to)) => Ok(RewriteRule::new(from.trim(), to.trim())),
+        None => Err(format!("Expected rewrite in form 'from=to'. Found {:?}", text)),
+    }
+}
+
+/// Parses a 'key=value' command line argument.
+pub fn parse_define(text: &str) -> Result<(String, String), String> {
+    match text.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("Expected define in form 'key=value'. Found {:?}", text)),
+    }
+}
+
+/// Adds values as an associative list of symbol pairs, returning its address.
+pub fn add_script_values(
+    data: &mut SimpleGarnishData,
+    values: &BTreeMap<String, ScriptValue>,
+) -> Result<usize, RuntimeError<DataError>> {
+    let mut pairs = vec![];
+
+    for (key, value) in values {
+        let key_addr = data.parse_add_symbol(key)?;
+        let value_addr = match value {
+            ScriptValue::Boolean(true) => data.add_true()?,
+            ScriptValue::Boolean(false) => data.add_false()?,
+            ScriptValue::Integer(i) => match i32::try_from(*i) {
+                Ok(i) => data.add_number(SimpleNumber::Integer(i))?,
+                Err(_) => data.add_number(SimpleNumber::Float(*i as f64))?,
+            },
+            ScriptValue::Float(f) => data.add_number(SimpleNumber::Float(*f))?,
+            ScriptValue::Text(s) => add_string(data, s)?,
+        };
+
+        pairs.push(data.add_pair((key_addr, value_addr))?);
+    }
+
+    data.start_list(pairs.len())?;
+    for pair in pairs {
+        data.add_to_list(pair, true)?;
+    }
+    Ok(data.end_list()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rewrite_trims_both_sides() {
+        assert_eq!(
+            parse_rewrite(" blog/* = posts/* "),
+            Ok(RewriteRule::new("blog/*", "posts/*"))
+        );
+    }
+
+    #[test]
+    fn parse_rewrite_requires_equals() {
+        assert!(parse_rewrite("blog/*").is_err());
+    }
+
+    #[test]
+    fn rewrite_exact_path() {
+        let rule = RewriteRule::new("/old/", "new");
+
+        assert_eq!(rule.apply("/old"), Some(String::from("new")));
+        assert_eq!(rule.apply("/old/page"), None);
+    }
+
+    #[test]
+    fn rewrite_prefix_replaces_star() {
+        let rule = RewriteRule::new("blog/*", "posts/*");
+
+        assert_eq!(rule.apply("/blog/first"), Some(String::from("posts/first")));
+        assert_eq!(rule.apply("/about"), None);
+    }
+
+    #[test]
+    fn first_matching_rewrite_is_used() {
+        let config = ServerConfig {
+            rewrites: vec![
+                RewriteRule::new("docs/api", "api"),
+                RewriteRule::new("docs/*", "pages/*"),
+            ],
+            ..ServerConfig::default()
+        };
+
+        assert_eq!(config.rewrite("/docs/api"), Some(String::from("api")));
+        assert_eq!(config.rewrite("/docs/intro"), Some(String::from("pages/intro")));
+    }
+
+    #[test]
+    fn defines_take_priority_over_environment_and_file_values() {
+        let config = ScriptConfig {
+            env_prefix: String::from("GARNISH_WEB_CONFIG_TEST_PRIORITY_"),
+            expose: vec![String::from("from_*")],
+            values: ["from_file", "from_env", "from_define"]
+                .iter()
+                .map(|key| (key.to_string(), ScriptValue::Text(String::from("file"))))
+                .collect(),
+        };
+
+        std::env::set_var("GARNISH_WEB_CONFIG_TEST_PRIORITY_FROM_ENV", "env");
+        std::env::set_var("GARNISH_WEB_CONFIG_TEST_PRIORITY_FROM_DEFINE", "env");
+        std::env::set_var("GARNISH_WEB_CONFIG_TEST_PRIORITY_HIDDEN", "env");
+
+        let values =
+            config.resolve_values(&vec![(String::from("from_define"), String::from("define"))]);
+
+        assert_eq!(values.get("from_file"), Some(&ScriptValue::Text(String::from("file"))));
+        assert_eq!(values.get("from_env"), Some(&ScriptValue::Text(String::from("env"))));
+        assert_eq!(values.get("from_define"), Some(&ScriptValue::Text(String::from("define"))));
+        assert_eq!(values.get("hidden"), None);
+    }
+}
@@ -1,12 +1,26 @@
 use garnish_lang::simple::{DataError, SimpleGarnishData};
 use garnish_lang::{GarnishContext, GarnishData, RuntimeError};
 use garnish_lang_utilities::{BuildMetadata, DataInfoProvider};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use crate::config::{add_script_values, ScriptValue};
+use crate::native::NativeFunction;
+use crate::testing::TestInfo;
+
+/// Symbol scripts use to access config values.
+pub const CONFIG_SYMBOL: &str = "config";
 
 #[derive(Debug, Clone)]
 pub struct WebContext {
     expression_map: HashMap<String, usize>,
     build_metadata: Vec<BuildMetadata<SimpleGarnishData>>,
+    tests: Vec<TestInfo>,
+    // position in list is the value stored in External data
+    natives: Vec<NativeFunction>,
+    values: BTreeMap<String, ScriptValue>,
+    // previous index of each changed expression while recording, oldest first
+    expression_changes: Option<Vec<(String, Option<usize>)>>,
 }
 
 impl WebContext {
@@ -14,11 +28,102 @@ impl WebContext {
         Self {
             expression_map: HashMap::new(),
             build_metadata: vec![],
+            tests: vec![],
+            natives: vec![],
+            values: BTreeMap::new(),
+            expression_changes: None,
         }
     }
 
     pub fn insert_expression<T: Into<String>>(&mut self, name: T, table_index: usize) {
-        self.expression_map.insert(name.into(), table_index);
+        let name = name.into();
+        let previous = self.expression_map.insert(name.clone(), table_index);
+
+        if let Some(changes) = &mut self.expression_changes {
+            changes.push((name, previous));
+        }
+    }
+
+    pub fn remove_expression(&mut self, name: &str) -> Option<usize> {
+        let previous = self.expression_map.remove(name);
+
+        if let Some(changes) = &mut self.expression_changes {
+            changes.push((name.to_string(), previous));
+        }
+
+        previous
+    }
+
+    /// Starts recording changes to expressions, so they can be undone with [`WebContext::undo_expression_changes`].
+    pub fn record_expression_changes(&mut self) {
+        self.expression_changes = Some(vec![]);
+    }
+
+    /// Stops recording changes to expressions, keeping them.
+    pub fn keep_expression_changes(&mut self) {
+        self.expression_changes = None;
+    }
+
+    /// Stops recording changes to expressions, restoring each to what it was when recording started.
+    pub fn undo_expression_changes(&mut self) {
+        let changes = self.expression_changes.take().unwrap_or_default();
+
+        for (name, previous) in changes.into_iter().rev() {
+            match previous {
+                Some(index) => self.expression_map.insert(name, index),
+                None => self.expression_map.remove(&name),
+            };
+        }
+    }
+
+    pub fn expression(&self, name: &str) -> Option<usize> {
+        self.expression_map.get(name).cloned()
+    }
+
+    pub fn expressions(&self) -> &HashMap<String, usize> {
+        &self.expression_map
+    }
+
+    /// Registers a Rust function callable by name from scripts. Replaces any native with the same name.
+    pub fn insert_native(&mut self, native: NativeFunction) {
+        match self.natives.iter().position(|n| n.name() == native.name()) {
+            None => self.natives.push(native),
+            Some(i) => self.natives[i] = native,
+        }
+    }
+
+    pub fn natives(&self) -> &Vec<NativeFunction> {
+        &self.natives
+    }
+
+    /// Replaces values available to scripts through the 'config' symbol.
+    pub fn set_values(&mut self, values: BTreeMap<String, ScriptValue>) {
+        self.values = values;
+    }
+
+    pub fn insert_value<T: Into<String>>(&mut self, key: T, value: ScriptValue) {
+        self.values.insert(key.into(), value);
+    }
+
+    pub fn values(&self) -> &BTreeMap<String, ScriptValue> {
+        &self.values
+    }
+
+    pub fn insert_test(&mut self, test: TestInfo) {
+        self.tests.push(test);
+    }
+
+    pub fn remove_tests(&mut self, path: &PathBuf) {
+        self.tests.retain(|t| t.path() != path);
+    }
+
+    /// Removes the tests inserted after the first `at` tests, returning them.
+    pub fn split_off_tests(&mut self, at: usize) -> Vec<TestInfo> {
+        self.tests.split_off(at)
+    }
+
+    pub fn tests(&self) -> &Vec<TestInfo> {
+        &self.tests
     }
 
     pub fn metadata(&self) -> &Vec<BuildMetadata<SimpleGarnishData>> {
@@ -30,16 +135,29 @@ impl WebContext {
     }
 }
 
-impl GarnishContext<SimpleGarnishData> for WebContext {
-    fn resolve(
-        &mut self,
+impl WebContext {
+    /// Pushes the expression, config values or native function a symbol refers to. Returns false if it refers to nothing.
+    pub fn resolve_symbol(
+        &self,
         symbol: u64,
         data: &mut SimpleGarnishData,
     ) -> Result<bool, RuntimeError<DataError>> {
         match data.get_symbols().get(&symbol) {
             None => Ok(false),
             Some(s) => match self.expression_map.get(s) {
-                None => Ok(false),
+                None if s == CONFIG_SYMBOL => {
+                    add_script_values(data, &self.values)
+                        .and_then(|i| Ok(data.push_register(i)?))?;
+                    Ok(true)
+                }
+                None => match self.natives.iter().position(|n| n.name() == s) {
+                    None => Ok(false),
+                    Some(i) => {
+                        data.add_external(i)
+                            .and_then(|i| data.push_register(i))?;
+                        Ok(true)
+                    }
+                },
                 Some(i) => {
                     data.add_expression(*i)
                         .and_then(|i| data.push_register(i))?;
@@ -48,6 +166,73 @@ impl GarnishContext<SimpleGarnishData> for WebContext {
             },
         }
     }
+
+    /// Calls the native function stored in External data and pushes its result.
+    pub fn apply_native(
+        &self,
+        external_value: usize,
+        input_addr: usize,
+        data: &mut SimpleGarnishData,
+    ) -> Result<bool, RuntimeError<DataError>> {
+        match self.natives.get(external_value) {
+            None => Ok(false),
+            Some(native) => {
+                let result = native.call(input_addr, data)?;
+                data.push_register(result)?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+impl GarnishContext<SimpleGarnishData> for WebContext {
+    fn resolve(
+        &mut self,
+        symbol: u64,
+        data: &mut SimpleGarnishData,
+    ) -> Result<bool, RuntimeError<DataError>> {
+        self.resolve_symbol(symbol, data)
+    }
+
+    fn apply(
+        &mut self,
+        external_value: usize,
+        input_addr: usize,
+        data: &mut SimpleGarnishData,
+    ) -> Result<bool, RuntimeError<DataError>> {
+        self.apply_native(external_value, input_addr, data)
+    }
+}
+
+/// Borrows a [`WebContext`] so requests can execute against it without copying it.
+/// Resolving and applying don't change the context, only the data.
+pub struct SharedContext<'a> {
+    context: &'a WebContext,
+}
+
+impl<'a> SharedContext<'a> {
+    pub fn new(context: &'a WebContext) -> Self {
+        Self { context }
+    }
+}
+
+impl<'a> GarnishContext<SimpleGarnishData> for SharedContext<'a> {
+    fn resolve(
+        &mut self,
+        symbol: u64,
+        data: &mut SimpleGarnishData,
+    ) -> Result<bool, RuntimeError<DataError>> {
+        self.context.resolve_symbol(symbol, data)
+    }
+
+    fn apply(
+        &mut self,
+        external_value: usize,
+        input_addr: usize,
+        data: &mut SimpleGarnishData,
+    ) -> Result<bool, RuntimeError<DataError>> {
+        self.context.apply_native(external_value, input_addr, data)
+    }
 }
 
 impl DataInfoProvider<SimpleGarnishData> for WebContext {
@@ -77,6 +262,16 @@ impl DataInfoProvider<SimpleGarnishData> for WebContext {
                     None => Some(format!("Symbol resolves to expression: {} @ [no jump table index {}]", sym_name, p)),
                     Some(point) => Some(format!("Symbol resolves to expression: {} @ {}", sym_name, point)),
                 })
+                .or_else(|| match sym_name == CONFIG_SYMBOL {
+                    true => Some(format!("Symbol resolves to config values: {} keys", self.values.len())),
+                    false => None,
+                })
+                .or_else(|| {
+                    self.natives
+                        .iter()
+                        .position(|n| n.name() == sym_name)
+                        .map(|i| format!("Symbol resolves to native function: {} @ {{external - {}}}", sym_name, i))
+                })
         })
     }
 }
@@ -0,0 +1,730 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+
+use garnish_lang::simple::{SimpleGarnishData, SimpleGarnishRuntime, SimpleRuntimeState};
+use garnish_lang::{GarnishData, GarnishDataType, GarnishRuntime};
+use garnish_lang_utilities::{compile_metadata, simple_expression_data_format, BuildMetadata};
+use log::{debug, error, info};
+use serde_json::{json, Value};
+
+use crate::context::WebContext;
+use crate::{current_value_to_string, find_route, FileType, RouteInfo};
+
+const THREAD_ID: i64 = 1;
+const VALUES_REFERENCE: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 2;
+// list values are expanded by adding their data address to this offset
+const LIST_REFERENCE_OFFSET: i64 = 1000;
+
+/// Source position of a single instruction, created from the build metadata of the file it was compiled from.
+#[derive(Clone, Debug)]
+struct SourceLocation {
+    name: String,
+    path: PathBuf,
+    line: usize,
+    column: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum StepMode {
+    Continue,
+    Over { line: Option<usize>, depth: usize },
+    In { line: Option<usize> },
+    Out { depth: usize },
+}
+
+enum StopReason {
+    Breakpoint,
+    Step,
+    Ended,
+    Error(String),
+}
+
+/// Debug Adapter Protocol session that executes a single route against the compiled runtime.
+pub struct DapSession<'a> {
+    runtime: SimpleGarnishRuntime<SimpleGarnishData>,
+    context: WebContext,
+    route_mapping: &'a HashMap<String, RouteInfo>,
+    source_map: Vec<Option<SourceLocation>>,
+    breakpoints: HashMap<PathBuf, HashSet<usize>>,
+    sequence: i64,
+    route: Option<RouteInfo>,
+    stop_on_entry: bool,
+    configured: bool,
+    running: bool,
+}
+
+impl<'a> DapSession<'a> {
+    pub fn new(
+        runtime: SimpleGarnishRuntime<SimpleGarnishData>,
+        context: WebContext,
+        route_mapping: &'a HashMap<String, RouteInfo>,
+    ) -> Self {
+        let source_map = create_source_map(context.metadata());
+
+        Self {
+            runtime,
+            context,
+            route_mapping,
+            source_map,
+            breakpoints: HashMap::new(),
+            sequence: 1,
+            route: None,
+            stop_on_entry: false,
+            configured: false,
+            running: false,
+        }
+    }
+
+    /// Listens on localhost for a single client connection and runs the session over it.
+    pub fn serve_tcp(self, port: u16) -> Result<(), String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).or_else(|e| Err(e.to_string()))?;
+        info!("Waiting for debug client on 127.0.0.1:{}", port);
+
+        let (stream, address) = listener.accept().or_else(|e| Err(e.to_string()))?;
+        info!("Debug client connected from {}", address);
+
+        let reader = stream.try_clone().or_else(|e| Err(e.to_string()))?;
+        self.run(BufReader::new(reader), stream)
+    }
+
+    pub fn serve_stdio(self) -> Result<(), String> {
+        let stdin = std::io::stdin();
+        let stdout = std::io::stdout();
+        self.run(stdin.lock(), stdout.lock())
+    }
+
+    fn run<R: BufRead, W: Write>(mut self, mut reader: R, mut writer: W) -> Result<(), String> {
+        loop {
+            let message = match read_message(&mut reader)? {
+                None => {
+                    debug!("Debug client closed connection");
+                    return Ok(());
+                }
+                Some(m) => m,
+            };
+
+            if message["type"] != "request" {
+                debug!("Ignoring non request message: {}", message);
+                continue;
+            }
+
+            let command = message["command"].as_str().unwrap_or_default().to_string();
+            let arguments = message["arguments"].clone();
+            debug!("Debug request: {} {}", command, arguments);
+
+            let (body, events) = match self.handle_request(&command, &arguments) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Debug request {} failed: {}", command, e);
+                    let response = self.response(&message, false, json!({}), Some(e));
+                    write_message(&mut writer, &response)?;
+                    continue;
+                }
+            };
+
+            let response = self.response(&message, true, body, None);
+            write_message(&mut writer, &response)?;
+
+            for (event, body) in events {
+                let event = self.event(&event, body);
+                write_message(&mut writer, &event)?;
+            }
+
+            if command == "disconnect" {
+                return Ok(());
+            }
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        command: &str,
+        arguments: &Value,
+    ) -> Result<(Value, Vec<(String, Value)>), String> {
+        match command {
+            "initialize" => Ok((
+                json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsTerminateRequest": true,
+                }),
+                vec![("initialized".into(), json!({}))],
+            )),
+            "launch" => {
+                let path = arguments["route"].as_str().unwrap_or_default();
+                let method = arguments["method"].as_str().unwrap_or("GET");
+                self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+
+                let route = match find_route(self.route_mapping, method, path) {
+                    None => Err(format!("No garnish mapping found for route \"{}\"", path))?,
+                    Some(r) => r.clone(),
+                };
+
+                info!("Debugging route {}", route.route);
+                self.runtime
+                    .get_data_mut()
+                    .set_instruction_cursor(route.execution_start)
+                    .or_else(|e| Err(format!("Failed to set instruction cursor: {:?}", e)))?;
+                self.route = Some(route);
+
+                Ok((json!({}), self.start_if_ready()))
+            }
+            "setBreakpoints" => {
+                let path = arguments["source"]["path"].as_str().unwrap_or_default();
+                let lines = arguments["breakpoints"]
+                    .as_array()
+                    .map(|b| {
+                        b.iter()
+                            .filter_map(|b| b["line"].as_u64())
+                            .map(|l| l as usize)
+                            .collect::<Vec<usize>>()
+                    })
+                    .unwrap_or_default();
+
+                let (addresses, breakpoints) = self.resolve_breakpoints(path, &lines);
+                self.breakpoints.insert(normalize_path(path), addresses);
+
+                Ok((json!({ "breakpoints": breakpoints }), vec![]))
+            }
+            "configurationDone" => {
+                self.configured = true;
+                Ok((json!({}), self.start_if_ready()))
+            }
+            "threads" => Ok((
+                json!({ "threads": [{ "id": THREAD_ID, "name": "request" }] }),
+                vec![],
+            )),
+            "stackTrace" => Ok((self.stack_trace(), vec![])),
+            "scopes" => Ok((
+                json!({ "scopes": [
+                    { "name": "Values", "variablesReference": VALUES_REFERENCE, "expensive": false },
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                ]}),
+                vec![],
+            )),
+            "variables" => {
+                let reference = arguments["variablesReference"].as_i64().unwrap_or_default();
+                Ok((json!({ "variables": self.variables(reference) }), vec![]))
+            }
+            "continue" => Ok((
+                json!({ "allThreadsContinued": true }),
+                self.resume(StepMode::Continue),
+            )),
+            "next" => {
+                let mode = StepMode::Over {
+                    line: self.current_line(),
+                    depth: self.depth(),
+                };
+                Ok((json!({}), self.resume(mode)))
+            }
+            "stepIn" => {
+                let mode = StepMode::In {
+                    line: self.current_line(),
+                };
+                Ok((json!({}), self.resume(mode)))
+            }
+            "stepOut" => {
+                let mode = StepMode::Out {
+                    depth: self.depth(),
+                };
+                Ok((json!({}), self.resume(mode)))
+            }
+            "terminate" | "disconnect" => {
+                self.running = false;
+                Ok((json!({}), vec![("terminated".into(), json!({}))]))
+            }
+            c => Err(format!("Unsupported request {}", c)),
+        }
+    }
+
+    fn start_if_ready(&mut self) -> Vec<(String, Value)> {
+        if !self.configured || self.route.is_none() || self.running {
+            return vec![];
+        }
+
+        self.running = true;
+
+        let cursor = self.runtime.get_data().get_instruction_cursor();
+        if self.stop_on_entry {
+            vec![stopped_event("entry", None)]
+        } else if self.is_breakpoint(cursor) {
+            vec![stopped_event("breakpoint", None)]
+        } else {
+            self.resume(StepMode::Continue)
+        }
+    }
+
+    fn resume(&mut self, mode: StepMode) -> Vec<(String, Value)> {
+        if !self.running {
+            return vec![];
+        }
+
+        match self.execute(mode) {
+            StopReason::Breakpoint => vec![stopped_event("breakpoint", None)],
+            StopReason::Step => vec![stopped_event("step", None)],
+            StopReason::Ended => {
+                self.running = false;
+                let file_type = self
+                    .route
+                    .as_ref()
+                    .map(|r| r.file_type)
+                    .unwrap_or(FileType::HTML);
+                let output = current_value_to_string(self.runtime.get_data_mut(), file_type);
+
+                vec![
+                    (
+                        "output".into(),
+                        json!({ "category": "stdout", "output": format!("{}\n", output) }),
+                    ),
+                    ("exited".into(), json!({ "exitCode": 0 })),
+                    ("terminated".into(), json!({})),
+                ]
+            }
+            StopReason::Error(e) => {
+                self.running = false;
+                vec![
+                    (
+                        "output".into(),
+                        json!({ "category": "stderr", "output": format!("{}\n", e) }),
+                    ),
+                    ("exited".into(), json!({ "exitCode": 1 })),
+                    ("terminated".into(), json!({})),
+                ]
+            }
+        }
+    }
+
+    fn execute(&mut self, mode: StepMode) -> StopReason {
+        // a line compiles to several instructions, so breakpoints on the line execution resumed from
+        // are ignored until it moves to another line, otherwise continuing would stop on the same line again
+        let mut resumed_line = self.current_source_line();
+
+        loop {
+            match self
+                .runtime
+                .execute_current_instruction(Some(&mut self.context))
+            {
+                Err(e) => return StopReason::Error(format!("Failed to execute: {:?}", e)),
+                Ok(data) => match data.get_state() {
+                    SimpleRuntimeState::Running => (),
+                    SimpleRuntimeState::End => return StopReason::Ended,
+                },
+            }
+
+            if let Some(here) = self.current_source_line() {
+                if resumed_line.as_ref() != Some(&here) {
+                    resumed_line = None;
+                }
+            }
+
+            let cursor = self.runtime.get_data().get_instruction_cursor();
+            if resumed_line.is_none() && self.is_breakpoint(cursor) {
+                return StopReason::Breakpoint;
+            }
+
+            let line = self.current_line();
+            let stepped = match mode {
+                StepMode::Continue => false,
+                StepMode::Over { line: start, depth } => {
+                    line.is_some() && line != start && self.depth() <= depth
+                }
+                StepMode::In { line: start } => line.is_some() && line != start,
+                StepMode::Out { depth } => line.is_some() && self.depth() < depth,
+            };
+
+            if stepped {
+                return StopReason::Step;
+            }
+        }
+    }
+
+    fn resolve_breakpoints(&self, path: &str, lines: &Vec<usize>) -> (HashSet<usize>, Vec<Value>) {
+        let path = normalize_path(path);
+        let mut addresses = HashSet::new();
+
+        let breakpoints = lines
+            .iter()
+            .map(|line| {
+                // client lines start at 1, lexer lines start at 0
+                let matches = self
+                    .source_map
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(addr, location)| location.as_ref().map(|l| (addr, l)))
+                    .filter(|(_, l)| l.path == path && l.line + 1 == *line)
+                    .map(|(addr, _)| addr)
+                    .collect::<Vec<usize>>();
+
+                let verified = !matches.is_empty();
+                addresses.extend(matches);
+
+                json!({ "verified": verified, "line": line })
+            })
+            .collect();
+
+        (addresses, breakpoints)
+    }
+
+    fn is_breakpoint(&self, addr: usize) -> bool {
+        self.breakpoints.values().any(|b| b.contains(&addr))
+    }
+
+    fn current_line(&self) -> Option<usize> {
+        self.location(self.runtime.get_data().get_instruction_cursor())
+            .map(|l| l.line)
+    }
+
+    fn current_source_line(&self) -> Option<(PathBuf, usize)> {
+        self.location(self.runtime.get_data().get_instruction_cursor())
+            .map(|l| (l.path.clone(), l.line))
+    }
+
+    fn depth(&self) -> usize {
+        self.runtime.get_data().get_jump_path_vec().len()
+    }
+
+    fn location(&self, addr: usize) -> Option<&SourceLocation> {
+        self.source_map.get(addr).and_then(|l| l.as_ref())
+    }
+
+    fn stack_trace(&self) -> Value {
+        let data = self.runtime.get_data();
+
+        // current position followed by each return point in the jump path
+        let addresses = std::iter::once(data.get_instruction_cursor())
+            .chain(data.get_jump_path_vec().iter().rev().cloned())
+            .collect::<Vec<usize>>();
+
+        let frames = addresses
+            .iter()
+            .enumerate()
+            .map(|(id, addr)| match self.location(*addr) {
+                None => json!({
+                    "id": id,
+                    "name": format!("[instruction {}]", addr),
+                    "line": 0,
+                    "column": 0,
+                }),
+                Some(l) => json!({
+                    "id": id,
+                    "name": l.name,
+                    "source": {
+                        "name": l.path.file_name().map(|n| n.to_string_lossy().to_string()),
+                        "path": l.path.to_string_lossy().to_string(),
+                    },
+                    "line": l.line + 1,
+                    "column": l.column + 1,
+                }),
+            })
+            .collect::<Vec<Value>>();
+
+        json!({ "stackFrames": frames, "totalFrames": addresses.len() })
+    }
+
+    // garnish only has a single value stack and register set
+    // so every frame shares the same variables
+    fn variables(&self, reference: i64) -> Vec<Value> {
+        let data = self.runtime.get_data();
+
+        match reference {
+            VALUES_REFERENCE => {
+                let values = data
+                    .get_value_iter()
+                    .filter_map(|i| data.get_value(i))
+                    .collect::<Vec<usize>>();
+                let last = values.len().saturating_sub(1);
+
+                values
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(i, addr)| {
+                        let name = match i == last {
+                            true => String::from("$"),
+                            false => format!("[{}]", i),
+                        };
+                        self.variable(name, *addr)
+                    })
+                    .collect()
+            }
+            REGISTERS_REFERENCE => data
+                .get_registers()
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, addr)| self.variable(format!("[{}]", i), *addr))
+                .collect(),
+            r if r >= LIST_REFERENCE_OFFSET => {
+                let list = (r - LIST_REFERENCE_OFFSET) as usize;
+                let len = data.get_list_len(list).unwrap_or_default();
+
+                (0..len)
+                    .filter_map(|i| {
+                        data.get_list_item(list, (i as i32).into())
+                            .ok()
+                            .map(|addr| self.variable(format!("[{}]", i), addr))
+                    })
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    fn variable(&self, name: String, addr: usize) -> Value {
+        let data = self.runtime.get_data();
+        let data_type = data.get_data_type(addr).ok();
+        let reference = match data_type {
+            Some(GarnishDataType::List) => LIST_REFERENCE_OFFSET + addr as i64,
+            _ => 0,
+        };
+
+        json!({
+            "name": name,
+            "value": simple_expression_data_format(addr, data, &self.context, 0),
+            "type": data_type.map(|t| format!("{:?}", t)),
+            "variablesReference": reference,
+        })
+    }
+
+    fn response(
+        &mut self,
+        request: &Value,
+        success: bool,
+        body: Value,
+        message: Option<String>,
+    ) -> Value {
+        let seq = self.next_sequence();
+        json!({
+            "seq": seq,
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": success,
+            "message": message,
+            "body": body,
+        })
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Value {
+        let seq = self.next_sequence();
+        json!({
+            "seq": seq,
+            "type": "event",
+            "event": event,
+            "body": body,
+        })
+    }
+
+    fn next_sequence(&mut self) -> i64 {
+        let seq = self.sequence;
+        self.sequence += 1;
+        seq
+    }
+}
+
+fn stopped_event(reason: &str, text: Option<String>) -> (String, Value) {
+    (
+        "stopped".into(),
+        json!({
+            "reason": reason,
+            "text": text,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        }),
+    )
+}
+
+/// Maps each instruction address to the source position it was built from.
+fn create_source_map(
+    metadata: &Vec<BuildMetadata<SimpleGarnishData>>,
+) -> Vec<Option<SourceLocation>> {
+    compile_metadata(metadata)
+        .into_iter()
+        .map(|meta| {
+            meta.map(|(name, token)| {
+                // annotation builds are named "<path> -> <name>"
+                let path = name.split(" -> ").next().unwrap_or_default();
+                SourceLocation {
+                    path: normalize_path(path),
+                    name,
+                    line: token.get_line(),
+                    column: token.get_column(),
+                }
+            })
+        })
+        .collect()
+}
+
+fn normalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    path.as_ref()
+        .canonicalize()
+        .unwrap_or(path.as_ref().to_path_buf())
+}
+
+fn read_message<R: BufRead>(reader: &mut R) -> Result<Option<Value>, String> {
+    let mut content_length = None;
+
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .or_else(|e| Err(e.to_string()))?;
+        if read == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(length) = line.strip_prefix("Content-Length:") {
+            content_length = Some(
+                length
+                    .trim()
+                    .parse::<usize>()
+                    .or_else(|e| Err(e.to_string()))?,
+            );
+        }
+    }
+
+    let length = match content_length {
+        None => Err("Debug message missing Content-Length header")?,
+        Some(l) => l,
+    };
+
+    let mut content = vec![0; length];
+    reader
+        .read_exact(&mut content)
+        .or_else(|e| Err(e.to_string()))?;
+
+    serde_json::from_slice(&content)
+        .map(Some)
+        .or_else(|e| Err(e.to_string()))
+}
+
+fn write_message<W: Write>(writer: &mut W, message: &Value) -> Result<(), String> {
+    let content = message.to_string();
+    write!(
+        writer,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    )
+    .and_then(|_| writer.flush())
+    .or_else(|e| Err(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::create_runtime_from_sources;
+
+    use super::*;
+
+    fn request(seq: i64, command: &str, arguments: Value) -> Value {
+        json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+    }
+
+    fn run_session(source: &str, requests: Vec<Value>) -> Vec<Value> {
+        let (route_mapping, runtime, context) = create_runtime_from_sources(
+            vec![(PathBuf::from("/site/index.garnish"), source.to_string())],
+            "/site",
+        )
+        .unwrap();
+
+        let mut input = vec![];
+        for request in requests {
+            write_message(&mut input, &request).unwrap();
+        }
+
+        let mut output = vec![];
+        DapSession::new(runtime, context, &route_mapping)
+            .run(Cursor::new(input), &mut output)
+            .unwrap();
+
+        let mut reader = Cursor::new(output);
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+
+        messages
+    }
+
+    fn events(messages: &[Value]) -> Vec<String> {
+        messages
+            .iter()
+            .filter(|m| m["type"] == "event")
+            .map(|m| match m["event"] == "stopped" {
+                true => format!("stopped {}", m["body"]["reason"].as_str().unwrap()),
+                false => m["event"].as_str().unwrap().to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn continuing_from_a_breakpoint_runs_to_the_end() {
+        let messages = run_session(
+            "5 + 5\n",
+            vec![
+                request(1, "initialize", json!({})),
+                request(
+                    2,
+                    "setBreakpoints",
+                    json!({
+                        "source": { "path": "/site/index.garnish" },
+                        "breakpoints": [{ "line": 1 }],
+                    }),
+                ),
+                request(3, "launch", json!({ "route": "/" })),
+                request(4, "configurationDone", json!({})),
+                request(5, "stackTrace", json!({ "threadId": THREAD_ID })),
+                request(6, "continue", json!({ "threadId": THREAD_ID })),
+                request(7, "disconnect", json!({})),
+            ],
+        );
+
+        let breakpoints = messages
+            .iter()
+            .find(|m| m["command"] == "setBreakpoints")
+            .unwrap();
+        assert_eq!(breakpoints["body"]["breakpoints"][0]["verified"], true);
+
+        let trace = messages
+            .iter()
+            .find(|m| m["command"] == "stackTrace")
+            .unwrap();
+        assert_eq!(trace["body"]["stackFrames"][0]["line"], 1);
+
+        // the breakpoint's line compiles to several instructions, but only stops once
+        assert_eq!(
+            events(&messages),
+            vec![
+                "initialized",
+                "stopped breakpoint",
+                "output",
+                "exited",
+                "terminated",
+                "terminated"
+            ]
+        );
+    }
+
+    #[test]
+    fn launching_an_unknown_route_fails() {
+        let messages = run_session(
+            "5 + 5\n",
+            vec![
+                request(1, "launch", json!({ "route": "/missing" })),
+                request(2, "disconnect", json!({})),
+            ],
+        );
+
+        assert_eq!(messages[0]["command"], "launch");
+        assert_eq!(messages[0]["success"], false);
+    }
+}
@@ -0,0 +1,194 @@
+use std::path::Path;
+
+use glob::{MatchOptions, Pattern};
+use log::debug;
+
+use crate::EXCLUDE_PATTERNS_DEFAULT;
+
+pub const IGNORE_FILE_NAME: &str = ".garnishignore";
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Ordered list of .gitignore style patterns. The last pattern matching a path decides if it is ignored.
+///
+/// Supported syntax:
+/// - blank lines and lines starting with '#' are skipped
+/// - a leading '!' re-includes paths matched by earlier patterns
+/// - a leading or middle '/' anchors the pattern to the serve path, otherwise it matches at any depth
+/// - a trailing '/' only matches directories, ignoring everything inside them
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    rules: Vec<IgnoreRule>,
+}
+
+#[derive(Debug, Clone)]
+struct IgnoreRule {
+    text: String,
+    negated: bool,
+    // matches the path itself, or any directory containing it
+    patterns: Vec<Pattern>,
+}
+
+impl IgnoreRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Default exclude patterns followed by the ignore file in the serve path, if it exists.
+    pub fn load(serve_path: &Path) -> Result<Self, String> {
+        let mut rules = Self::new();
+        for pattern in EXCLUDE_PATTERNS_DEFAULT {
+            rules.add(pattern)?;
+        }
+
+        let path = serve_path.join(IGNORE_FILE_NAME);
+
+        if !path.is_file() {
+            return Ok(rules);
+        }
+
+        debug!("Loading ignore file {:?}", path);
+        let text = std::fs::read_to_string(&path).or_else(|e| Err(e.to_string()))?;
+
+        for line in text.lines() {
+            rules
+                .add(line)
+                .or_else(|e| Err(format!("Invalid pattern in {:?}. {}", path, e)))?;
+        }
+
+        Ok(rules)
+    }
+
+    pub fn add(&mut self, line: &str) -> Result<(), String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+
+        let (negated, pattern) = match line.strip_prefix('!') {
+            None => (false, line),
+            Some(p) => (true, p),
+        };
+
+        let (directory_only, pattern) = match pattern.strip_suffix('/') {
+            None => (false, pattern),
+            Some(p) => (true, p),
+        };
+
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+
+        let base = match anchored {
+            true => pattern.to_string(),
+            false => format!("**/{}", pattern),
+        };
+
+        let mut texts = vec![format!("{}/**", base)];
+        if !directory_only {
+            texts.push(base);
+        }
+
+        let mut patterns = vec![];
+        for text in texts {
+            patterns.push(
+                Pattern::new(&text).or_else(|e| Err(format!("{:?}: {}", line, e)))?,
+            );
+        }
+
+        self.rules.push(IgnoreRule {
+            text: line.to_string(),
+            negated,
+            patterns,
+        });
+
+        Ok(())
+    }
+
+    /// Checks a path relative to the serve path.
+    pub fn is_ignored(&self, relative_path: &Path) -> bool {
+        match self.rules.iter().rev().find(|r| {
+            r.patterns
+                .iter()
+                .any(|p| p.matches_path_with(relative_path, MATCH_OPTIONS))
+        }) {
+            None => false,
+            Some(rule) => {
+                debug!("Path {:?} matched ignore pattern {:?}", relative_path, rule.text);
+                !rule.negated
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn rules(lines: &[&str]) -> IgnoreRules {
+        let mut rules = IgnoreRules::new();
+        for line in lines {
+            rules.add(line).unwrap();
+        }
+        rules
+    }
+
+    fn ignored(rules: &IgnoreRules, path: &str) -> bool {
+        rules.is_ignored(&PathBuf::from(path))
+    }
+
+    #[test]
+    fn unanchored_pattern_matches_at_any_depth() {
+        let rules = rules(&["*.draft.garnish"]);
+
+        assert!(ignored(&rules, "post.draft.garnish"));
+        assert!(ignored(&rules, "blog/post.draft.garnish"));
+        assert!(!ignored(&rules, "blog/post.garnish"));
+    }
+
+    #[test]
+    fn anchored_pattern_only_matches_from_serve_path() {
+        let rules = rules(&["/drafts"]);
+
+        assert!(ignored(&rules, "drafts/post.garnish"));
+        assert!(!ignored(&rules, "blog/drafts/post.garnish"));
+    }
+
+    #[test]
+    fn directory_pattern_ignores_contents_but_not_files() {
+        let rules = rules(&["build/"]);
+
+        assert!(ignored(&rules, "build/page.garnish"));
+        assert!(ignored(&rules, "nested/build/page.garnish"));
+        assert!(!ignored(&rules, "build"));
+    }
+
+    #[test]
+    fn last_matching_pattern_decides() {
+        let rules = rules(&["drafts/", "!drafts/keep.garnish"]);
+
+        assert!(ignored(&rules, "drafts/post.garnish"));
+        assert!(!ignored(&rules, "drafts/keep.garnish"));
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let rules = rules(&["# notes.garnish", "   ", "\t"]);
+
+        assert!(!ignored(&rules, "notes.garnish"));
+    }
+
+    #[test]
+    fn load_starts_with_default_patterns() {
+        let rules = IgnoreRules::load(&PathBuf::from("path/that/does/not/exist")).unwrap();
+
+        assert!(ignored(&rules, ".hidden/page.garnish"));
+        assert!(ignored(&rules, "node_modules/package/page.garnish"));
+        assert!(!ignored(&rules, "page.garnish"));
+    }
+}
@@ -0,0 +1,442 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use garnish_lang::compiler::lex::{lex, TokenType};
+use garnish_lang::simple::{SimpleGarnishData, SimpleGarnishRuntime};
+use garnish_lang::{GarnishData, GarnishRuntime};
+use log::{debug, error, info, warn};
+
+use crate::context::{WebContext, CONFIG_SYMBOL};
+use crate::metrics::metrics;
+use crate::prelude::load_prelude;
+use crate::{compile_source, RouteInfo, SharedState};
+
+/// What one source file added to the runtime, so it can be replaced when the file changes.
+#[derive(Debug, Clone)]
+struct CompilationUnit {
+    text: String,
+    // expression map entries registered by the file, including its routes
+    definitions: Vec<(String, usize)>,
+    // identifiers used by the file, used to find dependents of changed definitions
+    references: HashSet<String>,
+    instruction_count: usize,
+}
+
+/// Compiles a site one file at a time, so a changed file can be recompiled without rebuilding the rest.
+///
+/// Garnish data can only be appended to, so recompiling a file adds its new instructions and points its routes and
+/// definitions at them. The replaced instructions stay in the data until they outnumber the live ones, at which point
+/// the whole site is rebuilt.
+///
+/// Symbols are resolved by name when executed, so files using a changed definition pick it up without being rebuilt.
+/// The dependency graph is used to report dependents whose references were removed.
+///
+/// The runtime and context are shared with the state created by [`SiteCompiler::to_state`], and only copied when a
+/// file changes while that state is still served.
+pub struct SiteCompiler {
+    base_path: String,
+    runtime: Arc<SimpleGarnishRuntime<SimpleGarnishData>>,
+    context: Arc<WebContext>,
+    route_mapping: HashMap<String, RouteInfo>,
+    prelude_definitions: HashMap<String, usize>,
+    units: BTreeMap<PathBuf, CompilationUnit>,
+    // files whose last compile failed, until they compile or are removed
+    errors: BTreeMap<PathBuf, String>,
+    dead_instructions: usize,
+}
+
+impl SiteCompiler {
+    pub fn new<T: Into<String>>(base_path: T) -> Result<Self, String> {
+        let mut runtime = SimpleGarnishRuntime::new(SimpleGarnishData::new());
+        let mut context = WebContext::new();
+
+        load_prelude(&mut runtime, &mut context)?;
+
+        Ok(Self {
+            base_path: base_path.into(),
+            prelude_definitions: context.expressions().clone(),
+            runtime: Arc::new(runtime),
+            context: Arc::new(context),
+            route_mapping: HashMap::new(),
+            units: BTreeMap::new(),
+            errors: BTreeMap::new(),
+            dead_instructions: 0,
+        })
+    }
+
+    /// Compiles all sources, in order.
+    pub fn from_sources<T: Into<String>>(
+        sources: Vec<(PathBuf, String)>,
+        base_path: T,
+    ) -> Result<Self, String> {
+        let mut compiler = Self::new(base_path)?;
+
+        for (path, text) in sources {
+            compiler.update_file(path, text)?;
+        }
+
+        Ok(compiler)
+    }
+
+    /// Compiles all sources, in order, leaving out files that fail to compile.
+    /// Their errors are kept, see [`SiteCompiler::build_errors`].
+    pub fn from_sources_allowing_errors<T: Into<String>>(
+        sources: Vec<(PathBuf, String)>,
+        base_path: T,
+    ) -> Result<Self, String> {
+        let mut compiler = Self::new(base_path)?;
+
+        for (path, text) in sources {
+            if let Err(e) = compiler.update_file(path.clone(), text) {
+                error!("Failed to compile {:?}. Leaving it out of the build. Reason: {}", path, e);
+            }
+        }
+
+        Ok(compiler)
+    }
+
+    pub fn runtime(&self) -> &SimpleGarnishRuntime<SimpleGarnishData> {
+        &self.runtime
+    }
+
+    pub fn context(&self) -> &WebContext {
+        &self.context
+    }
+
+    pub fn context_mut(&mut self) -> &mut WebContext {
+        Arc::make_mut(&mut self.context)
+    }
+
+    pub fn route_mapping(&self) -> &HashMap<String, RouteInfo> {
+        &self.route_mapping
+    }
+
+    pub fn files(&self) -> Vec<&PathBuf> {
+        self.units.keys().collect()
+    }
+
+    /// Errors of files whose last compile failed. Those files are served from their last successful build, if any.
+    pub fn build_errors(&self) -> Vec<String> {
+        self.errors
+            .iter()
+            .map(|(path, e)| format!("{:?}: {}", path, e))
+            .collect()
+    }
+
+    /// Shares the current build, and the errors of files that failed to compile, with state that can be served.
+    pub fn to_state(&self) -> SharedState {
+        SharedState::from_shared(
+            self.route_mapping.clone(),
+            self.runtime.clone(),
+            self.context.clone(),
+        )
+        .with_build_errors(self.build_errors())
+    }
+
+    pub fn into_parts(
+        self,
+    ) -> (
+        HashMap<String, RouteInfo>,
+        SimpleGarnishRuntime<SimpleGarnishData>,
+        WebContext,
+    ) {
+        (
+            self.route_mapping,
+            Arc::try_unwrap(self.runtime).unwrap_or_else(|runtime| (*runtime).clone()),
+            Arc::try_unwrap(self.context).unwrap_or_else(|context| (*context).clone()),
+        )
+    }
+
+    /// Compiles a new or changed file, replacing anything it previously registered.
+    /// If compiling fails, the file's previous build is kept so its routes keep working.
+    /// Returns the other files that reference definitions the file added or removed.
+    pub fn update_file(&mut self, path: PathBuf, text: String) -> Result<Vec<PathBuf>, String> {
+        if self.units.get(&path).map(|u| u.text == text).unwrap_or(false) {
+            debug!("File {:?} unchanged. Skipping.", path);
+            // changed back to its last successful build
+            self.errors.remove(&path);
+            return Ok(vec![]);
+        }
+
+        let runtime = Arc::make_mut(&mut self.runtime);
+        let context = Arc::make_mut(&mut self.context);
+
+        // the new build is compiled alongside the previous one, which is only removed once the new one compiles,
+        // so a failed compile only has to undo what it added
+        let jump_start = runtime.get_data().get_jump_table_len();
+        let instruction_start = runtime.get_data().get_instruction_len();
+        let metadata_start = context.metadata().len();
+        let test_start = context.tests().len();
+        let mut routes = HashMap::new();
+
+        context.record_expression_changes();
+
+        let result = compile_source(
+            &path,
+            &text,
+            self.base_path.as_str(),
+            runtime,
+            context,
+            &mut routes,
+        );
+        metrics().record_compile(result.is_ok());
+
+        let instruction_count = runtime.get_data().get_instruction_len() - instruction_start;
+
+        if let Err(e) = result {
+            context.undo_expression_changes();
+            context.metadata_mut().truncate(metadata_start);
+            context.split_off_tests(test_start);
+
+            self.dead_instructions += instruction_count;
+            self.errors.insert(path, e.clone());
+
+            return Err(e);
+        }
+
+        context.keep_expression_changes();
+
+        let unit = CompilationUnit {
+            definitions: context
+                .expressions()
+                .iter()
+                .filter(|(_, i)| **i >= jump_start)
+                .map(|(name, i)| (name.clone(), *i))
+                .collect(),
+            references: match lex(&text) {
+                Err(_) => HashSet::new(),
+                Ok(tokens) => tokens
+                    .iter()
+                    .filter(|t| t.get_token_type() == TokenType::Identifier)
+                    .map(|t| t.get_text().clone())
+                    .collect(),
+            },
+            instruction_count,
+            text,
+        };
+
+        // the new build's metadata and tests are set aside, since removing the previous build removes them by path
+        let mut metadata = context.metadata_mut().split_off(metadata_start);
+        let tests = context.split_off_tests(test_start);
+
+        let previous = self.remove_unit(&path);
+
+        let context = Arc::make_mut(&mut self.context);
+        context.metadata_mut().append(&mut metadata);
+        for test in tests {
+            context.insert_test(test);
+        }
+        self.route_mapping.extend(routes);
+
+        self.errors.remove(&path);
+
+        let mut changed: HashSet<String> = unit.definitions.iter().map(|d| d.0.clone()).collect();
+        let mut removed = HashSet::new();
+
+        if let Some(previous) = previous {
+            for (name, _) in previous.definitions {
+                if !changed.contains(&name) {
+                    removed.insert(name.clone());
+                }
+                changed.insert(name);
+            }
+        }
+
+        self.units.insert(path.clone(), unit);
+
+        let dependents = self.dependents(&changed, &path);
+        self.report_removed_references(&removed, &dependents);
+
+        if self.dead_instructions > self.runtime.get_data().get_instruction_len() / 2 {
+            self.rebuild()?;
+        }
+
+        Ok(dependents)
+    }
+
+    /// Removes everything a deleted file registered. Returns the files that referenced its definitions.
+    pub fn remove_file(&mut self, path: &PathBuf) -> Vec<PathBuf> {
+        self.errors.remove(path);
+
+        match self.remove_unit(path) {
+            None => vec![],
+            Some(unit) => {
+                let removed = unit
+                    .definitions
+                    .into_iter()
+                    .map(|d| d.0)
+                    .filter(|name| self.context.expression(name).is_none())
+                    .collect::<HashSet<String>>();
+
+                let dependents = self.dependents(&removed, path);
+                self.report_removed_references(&removed, &dependents);
+                dependents
+            }
+        }
+    }
+
+    /// Compiles every file again into new data, dropping replaced instructions.
+    /// Config values and natives registered on the context are kept.
+    pub fn rebuild(&mut self) -> Result<(), String> {
+        info!(
+            "Rebuilding site. {} of {} instructions are no longer used.",
+            self.dead_instructions,
+            self.runtime.get_data().get_instruction_len()
+        );
+
+        let mut compiler = Self::new(self.base_path.clone())?;
+
+        compiler.context_mut().set_values(self.context.values().clone());
+        for native in self.context.natives() {
+            compiler.context_mut().insert_native(native.clone());
+        }
+
+        for (path, unit) in self.units.iter() {
+            compiler.update_file(path.clone(), unit.text.clone())?;
+        }
+
+        compiler.errors = std::mem::take(&mut self.errors);
+        *self = compiler;
+
+        Ok(())
+    }
+
+    /// Files other than the given one that reference any of the names.
+    pub fn dependents(&self, names: &HashSet<String>, changed_path: &PathBuf) -> Vec<PathBuf> {
+        self.units
+            .iter()
+            .filter(|(path, _)| *path != changed_path)
+            .filter(|(_, unit)| unit.references.iter().any(|r| names.contains(r)))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    fn report_removed_references(&self, removed: &HashSet<String>, dependents: &Vec<PathBuf>) {
+        for path in dependents {
+            let unit = match self.units.get(path) {
+                None => continue,
+                Some(u) => u,
+            };
+
+            for name in removed.iter().filter(|n| unit.references.contains(*n)) {
+                let resolvable = self.context.expression(name).is_some()
+                    || self.context.natives().iter().any(|n| n.name() == name)
+                    || name == CONFIG_SYMBOL;
+
+                if !resolvable {
+                    warn!("{:?} references \"{}\", which is no longer defined", path, name);
+                }
+            }
+        }
+    }
+
+    fn remove_unit(&mut self, path: &PathBuf) -> Option<CompilationUnit> {
+        let unit = self.units.remove(path)?;
+        let context = Arc::make_mut(&mut self.context);
+
+        for (name, index) in unit.definitions.iter() {
+            // a later file may have replaced the definition
+            if context.expression(name) != Some(*index) {
+                continue;
+            }
+
+            context.remove_expression(name);
+
+            // fall back to the last other file, or the prelude, defining the same name
+            let fallback = self
+                .units
+                .values()
+                .rev()
+                .find_map(|u| u.definitions.iter().find(|d| &d.0 == name).map(|d| d.1))
+                .or_else(|| self.prelude_definitions.get(name).cloned());
+
+            if let Some(index) = fallback {
+                context.insert_expression(name.clone(), index);
+            }
+        }
+
+        let path_text = path.to_string_lossy().to_string();
+        let annotation_prefix = format!("{} -> ", path_text);
+
+        self.route_mapping.retain(|_, info| info.path() != path);
+        context.remove_tests(path);
+        context
+            .metadata_mut()
+            .retain(|m| m.get_name() != &path_text && !m.get_name().starts_with(&annotation_prefix));
+
+        self.dead_instructions += unit.instruction_count;
+
+        Some(unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+
+    fn page(text: &str) -> String {
+        format!("html` body` text` \"{}\"", text)
+    }
+
+    fn compiler() -> SiteCompiler {
+        SiteCompiler::from_sources(
+            vec![
+                (PathBuf::from("/site/index.garnish"), page("Home")),
+                (PathBuf::from("/site/about.garnish"), page("About")),
+            ],
+            "/site",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn failed_compile_keeps_the_previous_build() {
+        let mut compiler = compiler();
+        let path = PathBuf::from("/site/index.garnish");
+        let routes = compiler.route_mapping().clone();
+        let expressions = compiler.context().expressions().clone();
+        let metadata = compiler.context().metadata().len();
+
+        assert!(compiler.update_file(path.clone(), String::from("(((")).is_err());
+
+        assert_eq!(
+            compiler.route_mapping()["index"].execution_start(),
+            routes["index"].execution_start()
+        );
+        assert_eq!(compiler.context().expressions(), &expressions);
+        assert_eq!(compiler.context().metadata().len(), metadata);
+        assert_eq!(compiler.build_errors().len(), 1);
+
+        compiler.update_file(path, page("Changed")).unwrap();
+
+        assert_ne!(
+            compiler.route_mapping()["index"].execution_start(),
+            routes["index"].execution_start()
+        );
+        assert_eq!(compiler.route_mapping().len(), routes.len());
+        assert_eq!(compiler.context().metadata().len(), metadata);
+        assert!(compiler.build_errors().is_empty());
+    }
+
+    #[test]
+    fn state_shares_the_build_until_a_file_changes() {
+        let mut compiler = compiler();
+
+        let state = compiler.to_state();
+        assert!(ptr::eq(state.runtime(), compiler.runtime()));
+        assert!(ptr::eq(state.context(), compiler.context()));
+
+        compiler
+            .update_file(PathBuf::from("/site/about.garnish"), page("Changed"))
+            .unwrap();
+
+        assert!(!ptr::eq(state.runtime(), compiler.runtime()));
+        assert_ne!(
+            state.route_mapping()["about"].execution_start(),
+            compiler.route_mapping()["about"].execution_start()
+        );
+    }
+}
@@ -0,0 +1,1360 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::body::{boxed, Body, Full};
+use axum::extract::State;
+use axum::http::header::{
+    HeaderName, HeaderValue, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG,
+    IF_NONE_MATCH, LAST_MODIFIED, VARY,
+};
+use axum::http::{Method, Request, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::any;
+use axum::Router;
+use hyper::StatusCode;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use garnish_lang::compiler::{
+    build::build_with_data, build::InstructionMetadata, lex::LexerToken, lex::TokenType,
+    parse::parse, parse::ParseResult,
+};
+use garnish_lang::simple::{
+    DataError, SimpleGarnishData, SimpleGarnishRuntime, SimpleRuntimeState,
+};
+use garnish_lang::{
+    EmptyContext, GarnishContext, GarnishData, GarnishDataType, GarnishRuntime, RuntimeError,
+};
+use garnish_lang_annotations_collector::{Collector, PartBehavior, PartParser, Sink, TokenBlock};
+use garnish_lang_utilities::BuildMetadata;
+use hypertext_garnish::{Node, RuleSet};
+use serde_garnish::GarnishDataDeserializer;
+
+use crate::cache::{
+    cache_key, etag, is_not_modified, matches_none_match, vary_header, CachedResponse,
+    ResponseCache,
+};
+use crate::compression::find_precompressed;
+use crate::config::{CacheRule, ServerConfig};
+use crate::context::{SharedContext, WebContext};
+use crate::ignore::IgnoreRules;
+use crate::metrics::metrics;
+use crate::output::{render_css, render_html, request_output_mode, OutputMode};
+use crate::pool::RuntimePool;
+use crate::incremental::SiteCompiler;
+use crate::testing::TestInfo;
+
+pub use crate::builder::WebServerBuilder;
+
+pub mod access;
+pub mod artifact;
+mod builder;
+pub mod cache;
+pub mod compression;
+pub mod config;
+pub mod context;
+pub mod dap;
+pub mod ignore;
+pub mod incremental;
+pub mod metrics;
+pub mod mount;
+pub mod native;
+pub mod output;
+pub mod pool;
+pub mod prelude;
+pub mod probe;
+pub mod repl;
+pub mod server;
+pub mod snapshot;
+pub mod testing;
+pub mod tls;
+pub mod vhost;
+pub mod watch;
+
+pub const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const INCLUDE_PATTERN_DEFAULT: &str = "**/*.garnish";
+/// Hidden files and directories and installed packages are never compiled unless re-included.
+pub const EXCLUDE_PATTERNS_DEFAULT: [&str; 2] = [".*", "node_modules/"];
+
+/// Compiled site shared by every request. Each request executes against its own copy of the runtime,
+/// taken from a pool of copies reused across requests.
+#[derive(Clone)]
+pub struct SharedState {
+    base_runtime: Arc<SimpleGarnishRuntime<SimpleGarnishData>>,
+    runtimes: Arc<RuntimePool>,
+    context: Arc<WebContext>,
+    route_mapping: HashMap<String, RouteInfo>,
+    settings: ServerConfig,
+    cache: Arc<ResponseCache>,
+    built: SystemTime,
+    build_errors: Vec<String>,
+}
+
+impl SharedState {
+    pub fn new(
+        route_mapping: HashMap<String, RouteInfo>,
+        base_runtime: SimpleGarnishRuntime<SimpleGarnishData>,
+        context: WebContext,
+    ) -> Self {
+        Self::from_shared(route_mapping, Arc::new(base_runtime), Arc::new(context))
+    }
+
+    /// Serves a runtime and context that may be shared with the compiler that built them.
+    pub fn from_shared(
+        route_mapping: HashMap<String, RouteInfo>,
+        base_runtime: Arc<SimpleGarnishRuntime<SimpleGarnishData>>,
+        context: Arc<WebContext>,
+    ) -> Self {
+        Self {
+            runtimes: Arc::new(RuntimePool::new(&base_runtime)),
+            base_runtime,
+            context,
+            route_mapping,
+            settings: ServerConfig::default(),
+            cache: Arc::new(ResponseCache::default()),
+            built: SystemTime::now(),
+            build_errors: vec![],
+        }
+    }
+
+    /// Records files that failed to compile. The build is still served, without their routes.
+    pub fn with_build_errors(mut self, errors: Vec<String>) -> Self {
+        self.build_errors = errors;
+        self
+    }
+
+    /// Sets static directories, headers, limits, rewrites and cache rules used when serving requests.
+    /// Starts with an empty response cache.
+    pub fn with_settings(mut self, settings: ServerConfig) -> Self {
+        self.cache = Arc::new(ResponseCache::new(&settings.cache));
+        self.settings = settings;
+        self
+    }
+
+    pub fn route_mapping(&self) -> &HashMap<String, RouteInfo> {
+        &self.route_mapping
+    }
+
+    /// Compiled runtime each request starts from.
+    pub fn runtime(&self) -> &SimpleGarnishRuntime<SimpleGarnishData> {
+        &self.base_runtime
+    }
+
+    /// Copies of the runtime reused across requests.
+    pub fn runtimes(&self) -> &RuntimePool {
+        &self.runtimes
+    }
+
+    pub fn context(&self) -> &WebContext {
+        &self.context
+    }
+
+    pub fn settings(&self) -> &ServerConfig {
+        &self.settings
+    }
+
+    pub fn cache(&self) -> &ResponseCache {
+        &self.cache
+    }
+
+    /// When this build was created.
+    pub fn built(&self) -> SystemTime {
+        self.built
+    }
+
+    pub fn build_errors(&self) -> &Vec<String> {
+        &self.build_errors
+    }
+}
+
+/// Creates a router that sends every path to [`serve_request`].
+/// Can be nested or merged into another axum application, or used directly as a [`tower::Service`].
+pub fn create_router(state: Arc<SharedState>) -> Router {
+    Router::new()
+        .route("/", any(serve_request))
+        .route("/*path", any(serve_request))
+        .with_state(state)
+}
+
+/// Finds all files under the serve path matching any include pattern that aren't ignored.
+/// Exclude patterns use .gitignore syntax and are applied after the serve path's ignore file.
+pub fn find_source_files(
+    serve_path: &Path,
+    include: &Vec<String>,
+    exclude: &Vec<String>,
+) -> Result<Vec<PathBuf>, String> {
+    let mut ignore_rules = IgnoreRules::load(serve_path)?;
+    for pattern in exclude {
+        ignore_rules.add(pattern)?;
+    }
+
+    let mut paths = vec![];
+
+    for include_pattern in include {
+        let pattern = serve_path.join(include_pattern);
+
+        let glob_pattern = match pattern.to_str() {
+            None => Err(format!(
+                "Could not covert match pattern string. Path: {:?}",
+                pattern
+            ))?,
+            Some(s) => s,
+        };
+
+        let (oks, errs): (Vec<_>, Vec<_>) = glob::glob(glob_pattern)
+            .or_else(|e| Err(e.to_string()))?
+            .into_iter()
+            .partition(|g| g.is_ok());
+
+        for e in errs {
+            error!("Error during glob: {:?}", e);
+        }
+
+        for path in oks.into_iter().map(|g| g.unwrap()) {
+            let relative = path.strip_prefix(serve_path).unwrap_or(&path);
+
+            if ignore_rules.is_ignored(relative) {
+                debug!("Ignoring file: {:?}", path);
+            } else if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Applies server settings around [`handler`]. Rejects requests over the body limit,
+/// rewrites the path, serves files from static directories and adds configured headers.
+pub async fn serve_request(
+    State(state): State<Arc<SharedState>>,
+    mut request: Request<Body>,
+) -> Response {
+    let settings = &state.settings;
+    let start = Instant::now();
+    let site = request_site(&request);
+
+    let mut response = match check_body_limit(settings, &request) {
+        Some(response) => response,
+        None => {
+            if let Some(path) = settings.rewrite(request.uri().path()) {
+                rewrite_request(&mut request, &path);
+            }
+
+            match find_static_file(settings, request.uri().path()) {
+                Some(path) => serve_static_file(settings, &request, &path),
+                None => handler(State(state.clone()), request)
+                    .await
+                    .into_response(),
+            }
+        }
+    };
+
+    for (name, value) in settings.headers.iter() {
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                response.headers_mut().insert(name, value);
+            }
+            _ => warn!("Invalid configured header \"{}: {}\"", name, value),
+        }
+    }
+
+    let route = response
+        .extensions()
+        .get::<RouteKey>()
+        .map(|key| key.0.as_str())
+        .unwrap_or("");
+    metrics().record_request(&site, route, response.status().as_u16(), start.elapsed());
+
+    response
+}
+
+fn check_body_limit(settings: &ServerConfig, request: &Request<Body>) -> Option<Response> {
+    let max = settings.limits.max_body_bytes?;
+
+    let length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())?;
+
+    match length > max {
+        false => None,
+        true => {
+            info!("Rejecting request with body of {} bytes. Limit is {}", length, max);
+            Some(StatusCode::PAYLOAD_TOO_LARGE.into_response())
+        }
+    }
+}
+
+pub(crate) fn rewrite_request(request: &mut Request<Body>, path: &str) {
+    let uri = match request.uri().query() {
+        None => format!("/{}", path),
+        Some(q) => format!("/{}?{}", path, q),
+    };
+
+    match uri.parse::<Uri>() {
+        Err(e) => warn!("Rewritten path {:?} is not a valid uri. Reason: {}", uri, e),
+        Ok(uri) => {
+            debug!("Rewriting {} to {}", request.uri(), uri);
+            *request.uri_mut() = uri;
+        }
+    }
+}
+
+fn find_static_file(settings: &ServerConfig, page: &str) -> Option<PathBuf> {
+    let page = Path::new(page.trim_matches('/'));
+
+    // only plain names so requests can't reach outside of the static directories
+    if page.as_os_str().is_empty() || !page.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+
+    settings
+        .static_dirs
+        .iter()
+        .map(|dir| dir.join(page))
+        .find(|path| path.is_file())
+}
+
+fn serve_static_file(settings: &ServerConfig, request: &Request<Body>, path: &Path) -> Response {
+    let precompressed = match settings.compression.precompressed {
+        true => find_precompressed(path, request, &settings.compression),
+        false => None,
+    };
+
+    let (file_path, encoding) = match &precompressed {
+        Some((sidecar, encoding)) => (sidecar.as_path(), Some(*encoding)),
+        None => (path, None),
+    };
+
+    match fs::read(file_path) {
+        Err(e) => {
+            error!("Failed to read static file {:?}. Reason: {}", file_path, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Ok(bytes) => {
+            info!("Serving static file {:?}", file_path);
+            let mut response = Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, static_content_type(path));
+
+            if let Some(encoding) = encoding {
+                response = response
+                    .header(CONTENT_ENCODING, encoding)
+                    .header(VARY, "accept-encoding");
+            }
+
+            response.body(boxed(Full::from(bytes))).unwrap()
+        }
+    }
+}
+
+fn static_content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") | Some("htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") | Some("mjs") => "text/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Route key matched by a request, added to its response's extensions for metrics and logging.
+#[derive(Clone, Debug)]
+pub struct RouteKey(pub String);
+
+/// Mount prefix or host pattern of the site chosen for a request, added to its extensions by the mount and host routers.
+/// Labels the request's metrics, so routes with the same key in different sites are kept apart.
+#[derive(Clone, Debug)]
+pub struct SiteKey(pub String);
+
+pub async fn handler(
+    State(state): State<Arc<SharedState>>,
+    request: Request<Body>,
+) -> Response<String> {
+    let page = request.uri().path().trim().trim_matches('/').trim();
+
+    info!("Request for route \"{}\"", page);
+
+    match find_route(&state.route_mapping, request.method().as_str(), page) {
+        None => {
+            info!("No garnish mapping found for route \"{}\"", page);
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(String::new())
+                .unwrap()
+        }
+        Some(info) => {
+            let mut response = route_response(&state, &request, info);
+            response
+                .extensions_mut()
+                .insert(RouteKey(info.route().to_string()));
+            response
+        }
+    }
+}
+
+/// Responds with the route's output, from the cache when allowed or by executing it.
+fn route_response(
+    state: &SharedState,
+    request: &Request<Body>,
+    info: &RouteInfo,
+) -> Response<String> {
+    let cache_rule = match request.method() {
+        &Method::GET | &Method::HEAD => route_cache_rule(state, info),
+        _ => None,
+    };
+    let mode = request_output_mode(&state.settings.output, request);
+    // the output mode can come from the query, so it's part of the key even when the query isn't
+    let key = cache_rule
+        .map(|rule| format!("{}\n{}", cache_key(rule, info.route(), request), mode));
+
+    if let Some(cached) = key.as_ref().and_then(|k| state.cache.get(k)) {
+        debug!("Using cached response for route \"{}\"", info.route());
+        return rendered_response(
+            request,
+            cached.body().clone(),
+            cached.etag(),
+            Some(cached.modified()),
+            cache_rule.map(|rule| cache_control(rule, info, cached.remaining())),
+            cache_rule.and_then(vary_header),
+        );
+    }
+
+    if precondition_failed(state, request, info, mode) {
+        debug!("Precondition failed for route \"{}\"", info.route());
+        return Response::builder()
+            .status(StatusCode::PRECONDITION_FAILED)
+            .body(String::new())
+            .unwrap();
+    }
+
+    // compiled data is modified during execution so each request needs its own copy,
+    // the context is only read from and can be shared
+    let mut runtime = state.runtimes.take(&state.base_runtime);
+    let mut context = SharedContext::new(&state.context);
+
+    match runtime
+        .get_data_mut()
+        .set_instruction_cursor(info.execution_start)
+    {
+        Err(e) => {
+            error!("Failed to set instructor cursor: {:?}", e);
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(String::new())
+                .unwrap();
+        }
+        Ok(()) => (),
+    }
+
+    let deadline = state
+        .settings
+        .limits
+        .request_timeout_secs
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+
+    match execute_runtime_until(&mut runtime, &mut context, deadline) {
+        Err(e) => {
+            error!("Failed to execute: {:?}", e);
+            metrics().record_runtime_error(&request_site(request), info.route());
+            state.runtimes.put(runtime, &state.base_runtime);
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(String::new())
+                .unwrap();
+        }
+        Ok(instructions) => {
+            metrics().record_instructions(&request_site(request), info.route(), instructions)
+        }
+    }
+
+    debug!("Result: {}", runtime.get_data().display_current_value());
+
+    let body = match try_render_current_value(runtime.get_data_mut(), info.file_type, mode) {
+        Err(e) => {
+            error!("Failed to deserialize garnish data to {:?}: {}", info.file_type, e);
+            metrics().record_deserialize_failure(
+                &request_site(request),
+                info.route(),
+                match info.file_type {
+                    FileType::HTML => "html",
+                    FileType::CSS => "css",
+                },
+            );
+            String::new()
+        }
+        Ok(body) => body,
+    };
+
+    state.runtimes.put(runtime, &state.base_runtime);
+
+    match cache_rule {
+        None => {
+            let tag = etag(&body);
+            rendered_response(request, body, &tag, None, info.cache_control().cloned(), None)
+        }
+        Some(rule) => {
+            let ttl = Duration::from_secs(rule.ttl_secs);
+            let cached = CachedResponse::new(body, ttl);
+            let response = rendered_response(
+                request,
+                cached.body().clone(),
+                cached.etag(),
+                Some(cached.modified()),
+                Some(cache_control(rule, info, ttl)),
+                vary_header(rule),
+            );
+
+            if let (Some(key), true) = (key, rule.ttl_secs > 0) {
+                state.cache.insert(key, cached);
+            }
+
+            response
+        }
+    }
+}
+
+/// The configured cache rule for the route, or the one its file opted into with a @Cache annotation.
+fn route_cache_rule<'a>(state: &'a SharedState, info: &'a RouteInfo) -> Option<&'a CacheRule> {
+    state
+        .settings
+        .cache_rule(info.route())
+        .or_else(|| info.cache_rule())
+}
+
+/// Whether a request other than GET or HEAD has an If-None-Match header matching the route's current response.
+/// Checked before the route runs, so a failed precondition doesn't cause the route's side effects.
+/// Only the cached response of the route is known without running it, otherwise only `*` matches.
+fn precondition_failed(
+    state: &SharedState,
+    request: &Request<Body>,
+    info: &RouteInfo,
+    mode: OutputMode,
+) -> bool {
+    match request.method() {
+        &Method::GET | &Method::HEAD => return false,
+        _ if !request.headers().contains_key(IF_NONE_MATCH) => return false,
+        _ => (),
+    }
+
+    let current = route_cache_rule(state, info).and_then(|rule| {
+        let key = format!("{}\n{}", cache_key(rule, info.route(), request), mode);
+        state.cache.get(&key)
+    });
+
+    matches_none_match(request, current.as_ref().map(|c| c.etag().as_str()))
+}
+
+fn request_site(request: &Request<Body>) -> String {
+    request
+        .extensions()
+        .get::<SiteKey>()
+        .map(|key| key.0.clone())
+        .unwrap_or_default()
+}
+
+/// Executes instructions from the current cursor until the runtime reaches the end of execution.
+pub fn execute_runtime<C: GarnishContext<SimpleGarnishData>>(
+    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
+    context: &mut C,
+) -> Result<(), RuntimeError<DataError>> {
+    execute_runtime_until(runtime, context, None).map(|_| ())
+}
+
+/// Same as [`execute_runtime`] but fails once the deadline has passed.
+/// Returns the number of instructions executed.
+pub fn execute_runtime_until<C: GarnishContext<SimpleGarnishData>>(
+    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
+    context: &mut C,
+    deadline: Option<Instant>,
+) -> Result<usize, RuntimeError<DataError>> {
+    let mut instructions = 0;
+
+    loop {
+        instructions += 1;
+
+        match runtime.execute_current_instruction(Some(&mut *context))?.get_state() {
+            SimpleRuntimeState::Running => (),
+            SimpleRuntimeState::End => return Ok(instructions),
+        }
+
+        if let Some(deadline) = deadline {
+            if Instant::now() > deadline {
+                return Err(RuntimeError::new_message(
+                    "Execution exceeded request timeout".to_string(),
+                ));
+            }
+        }
+    }
+}
+
+/// Builds the response for a route's output, or a 304 response if a GET or HEAD request's validators match it.
+/// Preconditions of other requests are checked before the route runs.
+fn rendered_response(
+    request: &Request<Body>,
+    body: String,
+    etag: &str,
+    last_modified: Option<SystemTime>,
+    cache_control: Option<String>,
+    vary: Option<String>,
+) -> Response<String> {
+    let mut response = Response::builder().header(ETAG, etag);
+
+    if let Some(modified) = last_modified {
+        response = response.header(LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+
+    if let Some(policy) = cache_control {
+        response = response.header(CACHE_CONTROL, policy);
+    }
+
+    if let Some(headers) = vary {
+        response = response.header(VARY, headers);
+    }
+
+    let safe = matches!(request.method(), &Method::GET | &Method::HEAD);
+
+    match safe && is_not_modified(request, etag, last_modified) {
+        true => response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(String::new())
+            .unwrap(),
+        false => response
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/html")
+            .body(body)
+            .unwrap(),
+    }
+}
+
+/// The rule's policy, then the route's @CacheControl annotation, then one based on the time left to live.
+fn cache_control(rule: &CacheRule, info: &RouteInfo, remaining: Duration) -> String {
+    match (&rule.cache_control, info.cache_control()) {
+        (Some(policy), _) | (None, Some(policy)) => policy.clone(),
+        (None, None) => format!("public, max-age={}", remaining.as_secs()),
+    }
+}
+
+pub fn find_route<'a>(
+    route_mapping: &'a HashMap<String, RouteInfo>,
+    method: &str,
+    page: &str,
+) -> Option<&'a RouteInfo> {
+    let page = page.trim().trim_matches('/').trim();
+    let page_index = match page.is_empty() {
+        true => String::from("index"),
+        false => [page, "index"].join("/"),
+    };
+    let page_method = format!("{}@{}", method, page);
+    let page_index_method = format!("{}@{}", method, page_index);
+
+    let options = [page_method, page_index_method, page.into(), page_index];
+
+    debug!("Checking options: {:?}", options);
+
+    // find first options that is in route mapping
+    // then get that option
+    options
+        .iter()
+        .find(|o| route_mapping.contains_key(*o))
+        .and_then(|s| route_mapping.get(s))
+}
+
+pub fn current_value_to_string(data: &mut SimpleGarnishData, file_type: FileType) -> String {
+    render_current_value(data, file_type, OutputMode::Compact)
+}
+
+/// Renders the runtime's current value as HTML or CSS, formatted for the output mode.
+/// Values that can't be rendered as the file type are logged and rendered as empty text.
+pub fn render_current_value(
+    data: &mut SimpleGarnishData,
+    file_type: FileType,
+    mode: OutputMode,
+) -> String {
+    match try_render_current_value(data, file_type, mode) {
+        Err(e) => {
+            error!("Failed to deserialize garnish data to {:?}: {}", file_type, e);
+            String::new()
+        }
+        Ok(output) => output,
+    }
+}
+
+/// Same as [`render_current_value`] but returns failures instead of logging them.
+pub fn try_render_current_value(
+    data: &mut SimpleGarnishData,
+    file_type: FileType,
+    mode: OutputMode,
+) -> Result<String, String> {
+    match file_type {
+        FileType::HTML => {
+            deserialize_current_value::<Node>(data).map(|node| render_html(&node, mode))
+        }
+        FileType::CSS => {
+            deserialize_current_value::<RuleSet>(data).map(|rules| render_css(&rules, mode))
+        }
+    }
+}
+
+fn deserialize_current_value<'de, T: Deserialize<'de>>(
+    data: &'de mut SimpleGarnishData,
+) -> Result<T, String> {
+    let mut deserializer = GarnishDataDeserializer::new(data);
+    T::deserialize(&mut deserializer).or_else(|e| Err(format!("{:?}{:?}", e.message(), e)))
+}
+
+#[derive(Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum FileType {
+    HTML,
+    CSS,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct RouteInfo {
+    route: String,
+    path: PathBuf,
+    file_type: FileType,
+    execution_start: usize,
+    #[serde(default)]
+    cache_control: Option<String>,
+    #[serde(default)]
+    cache_rule: Option<CacheRule>,
+}
+
+impl RouteInfo {
+    pub fn new<T: Into<String>>(
+        route: T,
+        path: PathBuf,
+        file_type: FileType,
+        execution_start: usize,
+    ) -> Self {
+        Self {
+            route: route.into(),
+            path,
+            file_type,
+            execution_start,
+            cache_control: None,
+            cache_rule: None,
+        }
+    }
+
+    /// Cache-Control header the route's file set with a @CacheControl annotation.
+    pub fn with_cache_control(mut self, cache_control: Option<String>) -> Self {
+        self.cache_control = cache_control;
+        self
+    }
+
+    /// Caching the route's file opted into with a @Cache annotation.
+    pub fn with_cache_rule(mut self, cache_rule: Option<CacheRule>) -> Self {
+        self.cache_rule = cache_rule;
+        self
+    }
+
+    pub fn route(&self) -> &String {
+        &self.route
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn execution_start(&self) -> usize {
+        self.execution_start
+    }
+
+    pub fn cache_control(&self) -> Option<&String> {
+        self.cache_control.as_ref()
+    }
+
+    pub fn cache_rule(&self) -> Option<&CacheRule> {
+        self.cache_rule.as_ref()
+    }
+}
+
+pub fn create_runtime(
+    paths: Vec<PathBuf>,
+    base_path: &str,
+) -> Result<
+    (
+        HashMap<String, RouteInfo>,
+        SimpleGarnishRuntime<SimpleGarnishData>,
+        WebContext,
+    ),
+    String,
+> {
+    let sources = read_sources(paths)?;
+
+    create_runtime_from_sources(sources, base_path)
+}
+
+/// Reads each file's contents, paired with its path.
+pub fn read_sources(paths: Vec<PathBuf>) -> Result<Vec<(PathBuf, String)>, String> {
+    let mut sources = vec![];
+
+    for path in paths {
+        let file_text = fs::read_to_string(&path).or_else(|e| Err(e.to_string()))?;
+        sources.push((path, file_text));
+    }
+
+    Ok(sources)
+}
+
+/// Compiles already loaded file contents. Routes are created from each path relative to the base path.
+pub fn create_runtime_from_sources(
+    sources: Vec<(PathBuf, String)>,
+    base_path: &str,
+) -> Result<
+    (
+        HashMap<String, RouteInfo>,
+        SimpleGarnishRuntime<SimpleGarnishData>,
+        WebContext,
+    ),
+    String,
+> {
+    // user definitions are compiled after and replace prelude definitions with the same name
+    Ok(SiteCompiler::from_sources(sources, base_path)?.into_parts())
+}
+
+/// Compiles one file's annotations and root expression, registering its routes and definitions.
+pub(crate) fn compile_source(
+    path: &PathBuf,
+    file_text: &str,
+    base_path: &str,
+    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
+    context: &mut WebContext,
+    route_to_expression: &mut HashMap<String, RouteInfo>,
+) -> Result<(), String> {
+    let (route, file_type) = path
+        .strip_prefix(base_path)
+        .and_then(|s| Ok(s.to_string_lossy().replace(".garnish", "")))
+        .and_then(|s| {
+            Ok(if s.ends_with(".html") {
+                (s.replace(".html", ""), FileType::HTML)
+            } else if s.ends_with(".css") {
+                (s.replace(".css", ""), FileType::CSS)
+            } else {
+                (s, FileType::HTML)
+            })
+        })
+        .or_else(|e| Err(e.to_string()))?;
+
+    debug!("Compiling file: {:?}", path.to_string_lossy().to_string());
+
+    let collector: Collector = Collector::new(vec![
+        Sink::new("@Method").part(PartParser::new(PartBehavior::UntilToken(
+            TokenType::Subexpression,
+        ))),
+        Sink::new("@Def").part(PartParser::new(PartBehavior::UntilToken(
+            TokenType::Subexpression,
+        ))),
+        Sink::new("@Test").part(PartParser::new(PartBehavior::UntilToken(
+            TokenType::Subexpression,
+        ))),
+        Sink::new("@Cache").part(PartParser::new(PartBehavior::UntilNewline)),
+        Sink::new("@CacheControl").part(PartParser::new(PartBehavior::UntilNewline)),
+    ]);
+
+    let blocks: Vec<TokenBlock> = collector.collect_tokens_from_input(file_text)?;
+
+    let (root_blocks, annotation_blocks): (Vec<TokenBlock>, Vec<TokenBlock>) = blocks
+        .into_iter()
+        .partition(|b| b.annotation_text().is_empty());
+
+    let (method_blocks, annotation_blocks): (Vec<_>, Vec<_>) = annotation_blocks
+        .into_iter()
+        .partition(|b| b.annotation_text() == &"@Method".to_string());
+
+    let (cache_blocks, annotation_blocks): (Vec<_>, Vec<_>) = annotation_blocks
+        .into_iter()
+        .partition(|b| b.annotation_text() == &"@Cache".to_string());
+
+    let (cache_control_blocks, annotation_blocks): (Vec<_>, Vec<_>) = annotation_blocks
+        .into_iter()
+        .partition(|b| b.annotation_text() == &"@CacheControl".to_string());
+
+    let (test_blocks, def_blocks): (Vec<_>, Vec<_>) = annotation_blocks
+        .into_iter()
+        .partition(|b| b.annotation_text() == &"@Test".to_string());
+
+    let cache_rule = handle_cache_annotations(cache_blocks, path, &route)?;
+    let cache_control = handle_cache_control_annotations(cache_control_blocks, path);
+
+    let mut method_metadata = handle_method_annotations(
+        method_blocks,
+        runtime,
+        context,
+        path,
+        &route,
+        file_type,
+        route_to_expression,
+    )?;
+
+    context.metadata_mut().append(&mut method_metadata);
+
+    for info in route_to_expression.values_mut().filter(|info| &info.path == path) {
+        info.cache_control = cache_control.clone();
+        info.cache_rule = cache_rule.clone();
+    }
+
+    let mut def_metadata = handle_def_annotations(def_blocks, runtime, context, path)?;
+
+    context.metadata_mut().append(&mut def_metadata);
+
+    let mut test_metadata = handle_test_annotations(test_blocks, runtime, context, path)?;
+
+    context.metadata_mut().append(&mut test_metadata);
+
+    let root_tokens = root_blocks
+        .into_iter()
+        .flat_map(|b| b.tokens_owned())
+        .collect::<Vec<LexerToken>>();
+
+    let source = root_tokens
+        .iter()
+        .map(|token| token.get_text().clone())
+        .collect::<Vec<String>>()
+        .join("");
+
+    let parsed = parse(&root_tokens)?;
+    if parsed.get_nodes().is_empty() {
+        debug!("No root script found in file {:?}. Skipping.", path);
+        return Ok(());
+    }
+
+    let index = runtime.get_data().get_jump_table_len();
+    let instruction_data = build_with_data(
+        parsed.get_root(),
+        parsed.get_nodes().clone(),
+        runtime.get_data_mut(),
+    )?;
+    let execution_start = match runtime.get_data().get_jump_point(index) {
+        Some(i) => i,
+        None => Err(format!("No jump point found after building {:?}", path))?,
+    };
+
+    let root_metadata = BuildMetadata::new(
+        format!("{}", path.to_string_lossy().to_string()),
+        source,
+        execution_start,
+        root_tokens,
+        parsed,
+        instruction_data,
+    );
+
+    context.metadata_mut().push(root_metadata);
+
+    info!("Registering route: {}", route);
+    route_to_expression.insert(
+        route.clone(),
+        RouteInfo::new(route.clone(), path.clone(), file_type, execution_start)
+            .with_cache_control(cache_control)
+            .with_cache_rule(cache_rule),
+    );
+    context.insert_expression(route.clone(), index);
+
+    Ok(())
+}
+
+fn handle_def_annotations(
+    blocks: Vec<TokenBlock>,
+    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
+    context: &mut WebContext,
+    path: &PathBuf,
+) -> Result<Vec<BuildMetadata<SimpleGarnishData>>, String> {
+    let mut builds = vec![];
+
+    for def in blocks {
+        let source = def
+            .tokens()
+            .iter()
+            .map(|token| token.get_text().clone())
+            .collect::<Vec<String>>()
+            .join("");
+
+        let (parsed, instruction_data, name, start) =
+            match build_and_get_parameters(def.tokens(), runtime, path) {
+                Err(s) => {
+                    error!("{}", s);
+                    continue;
+                }
+                Ok(v) => v,
+            };
+
+        builds.push(BuildMetadata::new(
+            format!("{} -> {}", path.to_string_lossy().to_string(), name.clone()),
+            source,
+            start,
+            def.tokens_owned(),
+            parsed,
+            instruction_data,
+        ));
+
+        debug!("Found method: {}", name);
+        context.insert_expression(name, start);
+    }
+
+    Ok(builds)
+}
+
+fn handle_test_annotations(
+    blocks: Vec<TokenBlock>,
+    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
+    context: &mut WebContext,
+    path: &PathBuf,
+) -> Result<Vec<BuildMetadata<SimpleGarnishData>>, String> {
+    let mut builds = vec![];
+
+    for test in blocks {
+        let source = test
+            .tokens()
+            .iter()
+            .map(|token| token.get_text().clone())
+            .collect::<Vec<String>>()
+            .join("");
+
+        let (parsed, instruction_data, name, start) =
+            match build_and_get_parameters(test.tokens(), runtime, path) {
+                Err(s) => {
+                    error!("{}", s);
+                    continue;
+                }
+                Ok(v) => v,
+            };
+
+        builds.push(BuildMetadata::new(
+            format!("{} -> {}", path.to_string_lossy().to_string(), name.clone()),
+            source,
+            start,
+            test.tokens_owned(),
+            parsed,
+            instruction_data,
+        ));
+
+        debug!("Found test: {}", name);
+        context.insert_test(TestInfo::new(path.clone(), name, start));
+    }
+
+    Ok(builds)
+}
+
+/// Text of the rest of an annotation's line.
+fn annotation_line(block: &TokenBlock) -> String {
+    block
+        .parts()
+        .iter()
+        .flatten()
+        .map(|token| token.get_text().clone())
+        .collect::<Vec<String>>()
+        .join("")
+        .trim()
+        .to_string()
+}
+
+/// Response caching for every route of the file. The annotation's line has the number of seconds to keep a response,
+/// then optionally 'query' to keep one for each query string, and names of request headers to keep one for each value of.
+/// For example '@Cache 300 query accept-language'. The last annotation is used if there are several.
+fn handle_cache_annotations(
+    blocks: Vec<TokenBlock>,
+    path: &PathBuf,
+    route: &str,
+) -> Result<Option<CacheRule>, String> {
+    let mut cache_rule = None;
+
+    for block in blocks {
+        let line = annotation_line(&block);
+        let mut words = line.split_whitespace();
+
+        let ttl_secs = match words.next().map(|w| w.parse::<u64>()) {
+            Some(Ok(secs)) => secs,
+            _ => Err(format!(
+                "Cache annotation in {:?} needs the number of seconds to cache responses for. Found \"{}\"",
+                path, line
+            ))?,
+        };
+
+        let mut rule = CacheRule::new(route, ttl_secs);
+        for word in words {
+            match word {
+                "query" => rule.vary_query = true,
+                header => rule.vary_headers.push(header.to_string()),
+            }
+        }
+
+        debug!("Found cache annotation: {}", line);
+        cache_rule = Some(rule);
+    }
+
+    Ok(cache_rule)
+}
+
+/// Cache-Control header for every route of the file, taken as written from the rest of the annotation's line.
+/// The last annotation is used if there are several.
+fn handle_cache_control_annotations(blocks: Vec<TokenBlock>, path: &PathBuf) -> Option<String> {
+    let mut cache_control = None;
+
+    for block in blocks {
+        let policy = annotation_line(&block);
+
+        match policy.as_str() {
+            "" => warn!("Empty cache control annotation in {:?}", &path),
+            policy => {
+                debug!("Found cache policy: {}", policy);
+                cache_control = Some(policy.to_string());
+            }
+        }
+    }
+
+    cache_control
+}
+
+fn handle_method_annotations(
+    blocks: Vec<TokenBlock>,
+    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
+    context: &mut WebContext,
+    path: &PathBuf,
+    route: &String,
+    file_type: FileType,
+    route_to_expression: &mut HashMap<String, RouteInfo>,
+) -> Result<Vec<BuildMetadata<SimpleGarnishData>>, String> {
+    let mut builds = vec![];
+
+    for method in blocks {
+        let source = method
+            .tokens()
+            .iter()
+            .map(|token| token.get_text().clone())
+            .collect::<Vec<String>>()
+            .join("");
+        let (parsed, instruction_data, name, jump_index) =
+            match build_and_get_parameters(method.tokens(), runtime, path) {
+                Err(_) => continue,
+                Ok(v) => v,
+            };
+
+        // http method expressions use direct jump point instead of jump table reference that is stored in the Expression data type
+        let start = match runtime.get_data().get_jump_point(jump_index) {
+            None => {
+                error!(
+                    "Jump table reference not found. Searching for {}",
+                    jump_index
+                );
+                return Err("Expression value not found in jump table".into());
+            }
+            Some(s) => s,
+        };
+
+        builds.push(BuildMetadata::new(
+            format!("{} -> {}", path.to_string_lossy().to_string(), name.clone()),
+            source,
+            start,
+            method.tokens_owned(),
+            parsed,
+            instruction_data,
+        ));
+
+        info!("Registering route: {}@{}", name, route);
+        let route = format!("{}@{}", name, route);
+        route_to_expression.insert(
+            route.clone(),
+            RouteInfo::new(&route, path.clone(), file_type, start),
+        );
+        context.insert_expression(route.clone(), jump_index);
+    }
+
+    Ok(builds)
+}
+
+fn build_and_get_parameters(
+    tokens: &Vec<LexerToken>,
+    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
+    path: &PathBuf,
+) -> Result<(ParseResult, Vec<InstructionMetadata>, String, usize), String> {
+    let parsed = parse(tokens)?;
+    if parsed.get_nodes().is_empty() {
+        warn!("Empty method annotation in {:?}", &path);
+        return Err("Empty annotation".into());
+    }
+
+    let index = runtime.get_data().get_jump_table_len();
+    let instruction_data = build_with_data(
+        parsed.get_root(),
+        parsed.get_nodes().clone(),
+        runtime.get_data_mut(),
+    )?;
+    let execution_start = match runtime.get_data().get_jump_point(index) {
+        Some(i) => i,
+        None => Err(format!("No jump point found after building {:?}", &path))?,
+    };
+
+    // executing from this start should result in list with annotation parameters
+    match runtime
+        .get_data_mut()
+        .set_instruction_cursor(execution_start)
+    {
+        Err(e) => {
+            error!(
+                "Failed to set instructor cursor during annotation build: {:?}",
+                e
+            );
+            return Err("Couldn't set cursor".into());
+        }
+        Ok(()) => (),
+    }
+
+    loop {
+        match runtime.execute_current_instruction::<EmptyContext>(None) {
+            Err(e) => {
+                error!("Failure during annotation execution: {:?}", e);
+                continue;
+            }
+            Ok(data) => match data.get_state() {
+                SimpleRuntimeState::Running => (),
+                SimpleRuntimeState::End => break,
+            },
+        }
+    }
+
+    let value_ref = match runtime.get_data().get_current_value() {
+        None => {
+            error!("No value after annotation execution. Expected value of type List.");
+            return Err("No value after execution".into());
+        }
+        Some(v) => v,
+    };
+
+    let (name, start) =
+        get_name_expression_annotation_parameters(runtime, value_ref).or(Err(String::new()))?;
+
+    Ok((parsed, instruction_data, name, start))
+}
+
+fn get_name_expression_annotation_parameters(
+    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
+    value_ref: usize,
+) -> Result<(String, usize), ()> {
+    match runtime.get_data().get_data_type(value_ref) {
+        Err(_) => {
+            error!("Failed to retrieve value data type after annotation execution.");
+            Err(())
+        }
+        Ok(t) => match t {
+            GarnishDataType::List => {
+                // check for 2 values in list
+                let method_name = match runtime.get_data().get_list_item(value_ref, 0.into()) {
+                    Err(e) => {
+                        error!(
+                            "Failed to retrieve list item 0 for annotation list value. {:?}",
+                            e
+                        );
+                        return Err(());
+                    }
+                    Ok(v) => match runtime.get_data().get_data_type(v) {
+                        Err(_) => {
+                            error!("Failed to retrieve value data type for annotation list value.");
+                            return Err(());
+                        }
+                        Ok(t) => match t {
+                            GarnishDataType::Symbol => {
+                                match runtime.get_data().get_symbol(v) {
+                                    Err(_) => {
+                                        error!("No data found for annotation list value item 0");
+                                        return Err(());
+                                    }
+                                    Ok(s) => match runtime.get_data().get_symbols().get(&s) {
+                                        None => {
+                                            error!("Symbol with value {} not found in data symbol table", s);
+                                            return Err(());
+                                        }
+                                        Some(s) => s.clone(),
+                                    },
+                                }
+                            }
+                            GarnishDataType::CharList => {
+                                match runtime.get_data().get_data().get(v) {
+                                    None => {
+                                        error!("No data found for annotation list value item 0");
+                                        return Err(());
+                                    }
+                                    Some(s) => match s.as_char_list() {
+                                        Err(e) => {
+                                            error!("Value stored in Character List slot {} could not be cast to Character List. {:?}", v, e);
+                                            return Err(());
+                                        }
+                                        Ok(s) => s,
+                                    },
+                                }
+                            }
+                            _ => {
+                                error!("Expected Character List or Symbol type as first parameter in annotation list value");
+                                return Err(());
+                            }
+                        },
+                    },
+                };
+
+                let execution_start = match runtime.get_data().get_list_item(value_ref, 1.into()) {
+                    Err(e) => {
+                        error!(
+                            "Failed to retrieve list item 1 for annotation list value. {:?}",
+                            e
+                        );
+                        return Err(());
+                    }
+                    Ok(v) => match runtime.get_data().get_data_type(v) {
+                        Err(_) => {
+                            error!("Failed to retrieve value data type for annotation list value.");
+                            return Err(());
+                        }
+                        Ok(t) => match t {
+                            GarnishDataType::Expression => {
+                                match runtime.get_data().get_expression(v) {
+                                    Err(_) => {
+                                        error!("No data found for annotation list value item 0");
+                                        return Err(());
+                                    }
+                                    Ok(s) => s,
+                                }
+                            }
+                            _ => {
+                                error!("Expected Expression type as second parameter in annotation list value");
+                                return Err(());
+                            }
+                        },
+                    },
+                };
+
+                Ok((method_name, execution_start))
+            }
+            t => {
+                warn!(
+                    "Expected List data type after annotation execution. Found {:?}",
+                    t
+                );
+                Err(())
+            }
+        },
+    }
+}
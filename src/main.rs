@@ -1,64 +1,72 @@
-use std::collections::HashMap;
 use std::env::current_dir;
 use std::fs;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use axum::body::Body;
-use axum::extract::State;
-use axum::http::Request;
-use axum::response::Response;
-use axum::routing::any;
-use axum::Router;
 use clap::Parser;
-use hyper::StatusCode;
-use log::{debug, error, info, warn};
-use serde::Deserialize;
-
-use garnish_lang::compiler::{
-    build::build_with_data, build::InstructionMetadata, lex::LexerToken, lex::TokenType,
-    parse::parse, parse::ParseResult,
-};
-use garnish_lang::simple::{SimpleGarnishData, SimpleGarnishRuntime, SimpleRuntimeState};
-use garnish_lang::{EmptyContext, GarnishData, GarnishDataType, GarnishRuntime};
-use garnish_lang_annotations_collector::{Collector, PartBehavior, PartParser, Sink, TokenBlock};
-use garnish_lang_utilities::{
-    create_execution_dump, format_build_info, format_runtime, BuildMetadata,
+use log::{debug, error, LevelFilter};
+use simple_logger::SimpleLogger;
+
+use garnish_lang::{GarnishData, GarnishRuntime};
+use garnish_lang_utilities::{create_execution_dump, format_build_info, format_runtime};
+use garnish_web_server::artifact::{load_artifact, save_artifact};
+use garnish_web_server::config::{ProjectConfig, ScriptValue};
+use garnish_web_server::dap::DapSession;
+use garnish_web_server::incremental::SiteCompiler;
+use garnish_web_server::mount::{create_mount_router, MountTable};
+use garnish_web_server::probe::with_probes;
+use garnish_web_server::repl::run_repl;
+use garnish_web_server::server::serve;
+use garnish_web_server::snapshot::run_snapshots;
+use garnish_web_server::testing::run_tests;
+use garnish_web_server::vhost::{create_host_router, HostTable, DEFAULT_HOST_PATTERN};
+use garnish_web_server::watch::{create_live_router, watch_sources, LiveState};
+use garnish_web_server::{
+    create_router, create_runtime, find_source_files, read_sources, SharedState, WebServerBuilder,
 };
-use hypertext_garnish::{Node, RuleSet};
-use serde_garnish::GarnishDataDeserializer;
 
 use crate::args::{ServerArgs, ServerSubCommand};
-use crate::context::WebContext;
 
 mod args;
-mod context;
-
-pub const INCLUDE_PATTERN_DEFAULT: &str = "**/*.garnish";
-
-#[derive(Clone)]
-struct SharedState {
-    base_runtime: SimpleGarnishRuntime<SimpleGarnishData>,
-    context: WebContext,
-    route_mapping: HashMap<String, RouteInfo>,
-}
 
 #[tokio::main]
 async fn main() -> Result<(), String> {
-    simple_logger::init_with_env().unwrap();
-
     let args = ServerArgs::parse();
 
-    let mut serve_path = match args.serve_path {
+    let serve_path = match &args.serve_path {
         None => current_dir().or_else(|e| {
             Err(format!(
                 "Could not get current working directory. Caused by {:?}",
                 e
             ))
         })?,
-        Some(p) => p,
+        Some(p) => p.clone(),
+    };
+
+    let mut config = match &args.config {
+        None => ProjectConfig::load(&serve_path)?,
+        Some(path) => ProjectConfig::load_file(path)?,
     };
 
+    args.apply_overrides(&mut config);
+
+    match &config.server.logging.level {
+        None => simple_logger::init_with_env().unwrap(),
+        Some(level) => {
+            let level = LevelFilter::from_str(level)
+                .or_else(|_| Err(format!("Invalid log level {:?}", level)))?;
+
+            SimpleLogger::new().with_level(level).init().unwrap()
+        }
+    }
+
+    // stdout is reserved for protocol messages when debugging over stdio
+    if let ServerSubCommand::Dap { port: None } = args.command {
+        log::set_max_level(log::LevelFilter::Off);
+    }
+
     let serve_path_str = match serve_path.to_str() {
         None => Err(format!(
             "Could not covert serve path to string. Path: {:?}",
@@ -69,51 +77,124 @@ async fn main() -> Result<(), String> {
 
     debug!("Serving from path: {}", serve_path_str);
 
-    serve_path.push(INCLUDE_PATTERN_DEFAULT);
+    config.script.values = config.script.resolve_values(&args.defines);
 
-    let glob_pattern = match serve_path.to_str() {
-        None => Err(format!(
-            "Could not covert match pattern string. Path: {:?}",
-            serve_path
-        ))?,
-        Some(s) => s,
-    };
+    if let ServerSubCommand::Config = args.command {
+        println!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
+    if let ServerSubCommand::Serve {
+        mounts,
+        hosts,
+        watch,
+        watch_interval_ms,
+        artifact,
+        ..
+    } = &args.command
+    {
+        if let Some(artifact) = artifact {
+            let (route_mapping, runtime, mut context) = load_artifact(artifact)?;
+            context.set_values(config.script.values.clone());
+
+            let settings = config.server.with_base_path(&serve_path);
+            let state = Arc::new(
+                SharedState::new(route_mapping, runtime, context).with_settings(settings.clone()),
+            );
+
+            let app = with_probes(create_router(state.clone()), state, &settings.probes);
+            return serve(app, &settings).await;
+        }
+
+        if !hosts.is_empty() {
+            let mut table = HostTable::new();
 
-    let (oks, errs): (Vec<_>, Vec<_>) = glob::glob(glob_pattern)
-        .or_else(|e| Err(e.to_string()))?
-        .into_iter()
-        .partition(|g| g.is_ok());
+            for (host, prefix, path) in hosts {
+                debug!("Compiling host {}{} from path: {:?}", host, prefix, path);
+                table.mount(host, prefix, build_site(path, &args)?);
+            }
+
+            for (prefix, path) in mounts {
+                debug!("Compiling mount {} from path: {:?}", prefix, path);
+                table.mount(DEFAULT_HOST_PATTERN, prefix, build_site(path, &args)?);
+            }
+
+            let settings = config.server.with_base_path(&serve_path);
+            let app = with_probes(
+                create_host_router(table.clone()),
+                Arc::new(table),
+                &settings.probes,
+            );
+            return serve(app, &settings).await;
+        }
+
+        if !mounts.is_empty() {
+            let mut table = MountTable::new();
+
+            for (prefix, path) in mounts {
+                debug!("Compiling mount {} from path: {:?}", prefix, path);
+                table.mount(prefix, build_site(path, &args)?);
+            }
 
-    for e in errs {
-        error!("Error during glob: {:?}", e);
+            let settings = config.server.with_base_path(&serve_path);
+            let app = with_probes(
+                create_mount_router(table.clone()),
+                Arc::new(table),
+                &settings.probes,
+            );
+            return serve(app, &settings).await;
+        }
+
+        let paths = find_source_files(&serve_path, &config.server.include, &config.server.exclude)?;
+
+        // files that fail to compile are left out, and reported by the ready probe
+        let mut compiler = SiteCompiler::from_sources_allowing_errors(
+            read_sources(paths)?,
+            serve_path_str.as_str(),
+        )?;
+        compiler.context_mut().set_values(config.script.values.clone());
+
+        let mut settings = config.server.with_base_path(&serve_path);
+
+        if !*watch {
+            let state = Arc::new(compiler.to_state().with_settings(settings.clone()));
+
+            let app = with_probes(create_router(state.clone()), state, &settings.probes);
+            return serve(app, &settings).await;
+        }
+
+        // watching is for development, so requests may pick their own output mode
+        settings.output.query_override = true;
+
+        let live = Arc::new(LiveState::new(Arc::new(
+            compiler.to_state().with_settings(settings.clone()),
+        )));
+
+        tokio::spawn(watch_sources(
+            live.clone(),
+            compiler,
+            serve_path.clone(),
+            settings.clone(),
+            Duration::from_millis(*watch_interval_ms),
+        ));
+
+        let app = with_probes(create_live_router(live.clone()), live, &settings.probes);
+        return serve(app, &settings).await;
     }
 
-    let paths = oks
-        .into_iter()
-        .map(|g| g.unwrap())
-        .collect::<Vec<PathBuf>>();
+    let paths = find_source_files(&serve_path, &config.server.include, &config.server.exclude)?;
 
     let (route_mapping, mut runtime, mut context) = create_runtime(paths, serve_path_str.as_str())?;
 
+    context.set_values(config.script.values.clone());
+
+    let settings = config.server.with_base_path(&serve_path);
+
     match args.command {
-        ServerSubCommand::Serve => {
-            let state = Arc::new(SharedState {
-                route_mapping,
-                base_runtime: runtime,
-                context,
-            });
-
-            // build our application with a single route
-            let app = Router::new()
-                .route("/", any(handler))
-                .route("/*path", any(handler))
-                .with_state(state);
-
-            // run it with hyper on localhost:3000
-            axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-                .serve(app.into_make_service())
-                .await
-                .unwrap();
+        // both are handled before compiling
+        ServerSubCommand::Serve { .. } | ServerSubCommand::Config => (),
+        ServerSubCommand::Compile { artifact_path } => {
+            save_artifact(&artifact_path, &route_mapping, &runtime, &context)?
         }
         ServerSubCommand::Dump => {
             let metadata_output = context
@@ -131,9 +212,9 @@ async fn main() -> Result<(), String> {
                     Some(info) => {
                         match runtime
                             .get_data_mut()
-                            .set_instruction_cursor(info.execution_start)
+                            .set_instruction_cursor(info.execution_start())
                         {
-                            Ok(_) => debug!("Set instruction cursor to {}", info.execution_start),
+                            Ok(_) => debug!("Set instruction cursor to {}", info.execution_start()),
                             Err(e) => error!("Failed to set instruction cursor. Reason: {}", e),
                         }
                     }
@@ -200,522 +281,77 @@ async fn main() -> Result<(), String> {
                 }
             }
         }
-    }
-
-    Ok(())
-}
-
-async fn handler(
-    State(state): State<Arc<SharedState>>,
-    request: Request<Body>,
-) -> Response<String> {
-    let mut runtime = state.base_runtime.clone();
-    let mut context = state.context.clone();
-
-    let page = request.uri().path().trim().trim_matches('/').trim();
-    let page_index = match page.is_empty() {
-        true => String::from("index"),
-        false => [page, "index"].join("/"),
-    };
-    let page_method = format!("{}@{}", request.method(), page);
-    let page_index_method = format!("{}@{}", request.method(), page_index);
-
-    let options = [page_method, page_index_method, page.into(), page_index];
-
-    info!("Request for route \"{}\"", page);
-    debug!("Checking options: {:?}", options);
-
-    // find first options that is in route mapping
-    // then get that option
-    match options
-        .iter()
-        .find(|o| state.route_mapping.contains_key(*o))
-        .and_then(|s| state.route_mapping.get(s))
-    {
-        None => {
-            info!("No garnish mapping found for route \"{}\"", page);
-            Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(String::new())
-                .unwrap()
-        }
-        Some(info) => {
-            match runtime
-                .get_data_mut()
-                .set_instruction_cursor(info.execution_start)
-            {
-                Err(e) => {
-                    error!("Failed to set instructor cursor: {:?}", e);
-                    return Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(String::new())
-                        .unwrap();
-                }
-                Ok(()) => (),
-            }
-
-            loop {
-                match runtime.execute_current_instruction(Some(&mut context)) {
-                    Err(e) => {
-                        error!("Failed to execute: {:?}", e);
-                        return Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(String::new())
-                            .unwrap();
-                    }
-                    Ok(data) => match data.get_state() {
-                        SimpleRuntimeState::Running => (),
-                        SimpleRuntimeState::End => break,
-                    },
-                }
-            }
-
-            debug!("Result: {}", runtime.get_data().display_current_value());
-
-            Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "text/html")
-                .body(current_value_to_string(
-                    runtime.get_data_mut(),
-                    info.file_type,
-                ))
-                .unwrap()
-        }
-    }
-}
-
-fn current_value_to_string(data: &mut SimpleGarnishData, file_type: FileType) -> String {
-    match file_type {
-        FileType::HTML => deserialize_current_value::<Node>(data),
-        FileType::CSS => deserialize_current_value::<RuleSet>(data),
-    }
-}
-
-fn deserialize_current_value<'de, T: Deserialize<'de> + ToString>(
-    data: &'de mut SimpleGarnishData,
-) -> String {
-    let mut deserializer = GarnishDataDeserializer::new(data);
-    match T::deserialize(&mut deserializer) {
-        Err(e) => {
-            error!(
-                "Failed to deserialize garnish data to HTML: {:?}{:?}",
-                e.message(),
-                e
+        ServerSubCommand::Repl => run_repl(runtime, context)?,
+        ServerSubCommand::Test => run_tests(&runtime, &context)?,
+        ServerSubCommand::Snapshot {
+            snapshot_path,
+            update,
+        } => {
+            let state = Arc::new(
+                SharedState::new(route_mapping, runtime, context).with_settings(settings),
             );
-            String::new()
-        }
-        Ok(n) => n.to_string(),
-    }
-}
 
-#[derive(Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Debug)]
-enum FileType {
-    HTML,
-    CSS,
-}
-
-#[derive(Clone, Eq, PartialEq, Debug)]
-struct RouteInfo {
-    route: String,
-    file_type: FileType,
-    execution_start: usize,
-}
-
-impl RouteInfo {
-    pub fn new<T: Into<String>>(route: T, file_type: FileType, execution_start: usize) -> Self {
-        Self {
-            route: route.into(),
-            file_type,
-            execution_start,
+            run_snapshots(state, &snapshot_path, update).await?
         }
-    }
-}
+        ServerSubCommand::Dap { port } => {
+            let session = DapSession::new(runtime, context, &route_mapping);
 
-fn create_runtime(
-    paths: Vec<PathBuf>,
-    base_path: &str,
-) -> Result<
-    (
-        HashMap<String, RouteInfo>,
-        SimpleGarnishRuntime<SimpleGarnishData>,
-        WebContext,
-    ),
-    String,
-> {
-    let mut runtime = SimpleGarnishRuntime::new(SimpleGarnishData::new());
-    let mut context = WebContext::new();
-
-    // maps expected http route to index of expression that will be executed when that route is requested
-    let mut route_to_expression = HashMap::new();
-
-    for path in paths {
-        let (route, file_type) = path
-            .strip_prefix(base_path)
-            .and_then(|s| Ok(s.to_string_lossy().replace(".garnish", "")))
-            .and_then(|s| {
-                Ok(if s.ends_with(".html") {
-                    (s.replace(".html", ""), FileType::HTML)
-                } else if s.ends_with(".css") {
-                    (s.replace(".css", ""), FileType::CSS)
-                } else {
-                    (s, FileType::HTML)
-                })
-            })
-            .or_else(|e| Err(e.to_string()))?;
-
-        debug!("Compiling file: {:?}", path.to_string_lossy().to_string());
-
-        let file_text = fs::read_to_string(&path).or_else(|e| Err(e.to_string()))?;
-
-        let collector: Collector = Collector::new(vec![
-            Sink::new("@Method").part(PartParser::new(PartBehavior::UntilToken(
-                TokenType::Subexpression,
-            ))),
-            Sink::new("@Def").part(PartParser::new(PartBehavior::UntilToken(
-                TokenType::Subexpression,
-            ))),
-        ]);
-
-        let blocks: Vec<TokenBlock> = collector.collect_tokens_from_input(&file_text)?;
-
-        let (root_blocks, annotation_blocks): (Vec<TokenBlock>, Vec<TokenBlock>) = blocks
-            .into_iter()
-            .partition(|b| b.annotation_text().is_empty());
-
-        let (method_blocks, def_blocks): (Vec<_>, Vec<_>) = annotation_blocks
-            .into_iter()
-            .partition(|b| b.annotation_text() == &"@Method".to_string());
-
-        let mut method_metadata = handle_method_annotations(
-            method_blocks,
-            &mut runtime,
-            &mut context,
-            &path,
-            &route,
-            file_type,
-            &mut route_to_expression,
-        )?;
-
-        context.metadata_mut().append(&mut method_metadata);
-
-        let mut def_metadata =
-            handle_def_annotations(def_blocks, &mut runtime, &mut context, &path)?;
-
-        context.metadata_mut().append(&mut def_metadata);
-
-        let root_tokens = root_blocks
-            .into_iter()
-            .flat_map(|b| b.tokens_owned())
-            .collect::<Vec<LexerToken>>();
-
-        let source = root_tokens
-            .iter()
-            .map(|token| token.get_text().clone())
-            .collect::<Vec<String>>()
-            .join("");
-
-        let parsed = parse(&root_tokens)?;
-        if parsed.get_nodes().is_empty() {
-            debug!("No root script found in file {:?}. Skipping.", &path);
-            continue;
+            match port {
+                None => session.serve_stdio()?,
+                Some(port) => session.serve_tcp(port)?,
+            }
         }
-
-        let index = runtime.get_data().get_jump_table_len();
-        let instruction_data = build_with_data(
-            parsed.get_root(),
-            parsed.get_nodes().clone(),
-            runtime.get_data_mut(),
-        )?;
-        let execution_start = match runtime.get_data().get_jump_point(index) {
-            Some(i) => i,
-            None => Err(format!("No jump point found after building {:?}", &path))?,
-        };
-
-        let root_metadata = BuildMetadata::new(
-            format!("{}", path.to_string_lossy().to_string()),
-            source,
-            execution_start,
-            root_tokens,
-            parsed,
-            instruction_data,
-        );
-
-        context.metadata_mut().push(root_metadata);
-
-        info!("Registering route: {}", route);
-        route_to_expression.insert(
-            route.clone(),
-            RouteInfo::new(route.clone(), file_type, execution_start),
-        );
-        context.insert_expression(route.clone(), index)
     }
 
-    Ok((route_to_expression, runtime, context))
+    Ok(())
 }
 
-fn handle_def_annotations(
-    blocks: Vec<TokenBlock>,
-    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
-    context: &mut WebContext,
-    path: &PathBuf,
-) -> Result<Vec<BuildMetadata<SimpleGarnishData>>, String> {
-    let mut builds = vec![];
-
-    for def in blocks {
-        let source = def
-            .tokens()
-            .iter()
-            .map(|token| token.get_text().clone())
-            .collect::<Vec<String>>()
-            .join("");
-
-        let (parsed, instruction_data, name, start) =
-            match build_and_get_parameters(def.tokens(), runtime, path) {
-                Err(s) => {
-                    error!("{}", s);
-                    continue;
-                }
-                Ok(v) => v,
-            };
-
-        builds.push(BuildMetadata::new(
-            format!("{} -> {}", path.to_string_lossy().to_string(), name.clone()),
-            source,
-            start,
-            def.tokens_owned(),
-            parsed,
-            instruction_data,
-        ));
-
-        debug!("Found method: {}", name);
-        context.insert_expression(name, start);
-    }
+/// Compiles a directory with its own config file, overridden by command line flags.
+fn build_site(path: &PathBuf, args: &ServerArgs) -> Result<Arc<SharedState>, String> {
+    let mut config = ProjectConfig::load(path)?;
+    args.apply_overrides(&mut config);
 
-    Ok(builds)
-}
+    let mut builder = WebServerBuilder::new()
+        .serve_path(path)
+        .settings(config.server);
 
-fn handle_method_annotations(
-    blocks: Vec<TokenBlock>,
-    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
-    context: &mut WebContext,
-    path: &PathBuf,
-    route: &String,
-    file_type: FileType,
-    route_to_expression: &mut HashMap<String, RouteInfo>,
-) -> Result<Vec<BuildMetadata<SimpleGarnishData>>, String> {
-    let mut builds = vec![];
-
-    for method in blocks {
-        let source = method
-            .tokens()
-            .iter()
-            .map(|token| token.get_text().clone())
-            .collect::<Vec<String>>()
-            .join("");
-        let (parsed, instruction_data, name, jump_index) =
-            match build_and_get_parameters(method.tokens(), runtime, path) {
-                Err(_) => continue,
-                Ok(v) => v,
-            };
-
-        // http method expressions use direct jump point instead of jump table reference that is stored in the Expression data type
-        let start = match runtime.get_data().get_jump_point(jump_index) {
-            None => {
-                error!(
-                    "Jump table reference not found. Searching for {}",
-                    jump_index
-                );
-                return Err("Expression value not found in jump table".into());
-            }
-            Some(s) => s,
-        };
-
-        builds.push(BuildMetadata::new(
-            format!("{} -> {}", path.to_string_lossy().to_string(), name.clone()),
-            source,
-            start,
-            method.tokens_owned(),
-            parsed,
-            instruction_data,
-        ));
-
-        info!("Registering route: {}@{}", name, route);
-        let route = format!("{}@{}", name, route);
-        route_to_expression.insert(route.clone(), RouteInfo::new(&route, file_type, start));
-        context.insert_expression(route.clone(), jump_index);
+    for (key, value) in args.defines.iter() {
+        builder = builder.value(key, ScriptValue::Text(value.clone()));
     }
 
-    Ok(builds)
+    builder.allow_build_errors().build()
 }
 
-fn build_and_get_parameters(
-    tokens: &Vec<LexerToken>,
-    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
-    path: &PathBuf,
-) -> Result<(ParseResult, Vec<InstructionMetadata>, String, usize), String> {
-    let parsed = parse(tokens)?;
-    if parsed.get_nodes().is_empty() {
-        warn!("Empty method annotation in {:?}", &path);
-        return Err("Empty annotation".into());
-    }
-
-    let index = runtime.get_data().get_jump_table_len();
-    let instruction_data = build_with_data(
-        parsed.get_root(),
-        parsed.get_nodes().clone(),
-        runtime.get_data_mut(),
-    )?;
-    let execution_start = match runtime.get_data().get_jump_point(index) {
-        Some(i) => i,
-        None => Err(format!("No jump point found after building {:?}", &path))?,
-    };
-
-    // executing from this start should result in list with annotation parameters
-    match runtime
-        .get_data_mut()
-        .set_instruction_cursor(execution_start)
-    {
-        Err(e) => {
-            error!(
-                "Failed to set instructor cursor during annotation build: {:?}",
-                e
-            );
-            return Err("Couldn't set cursor".into());
-        }
-        Ok(()) => (),
-    }
-
-    loop {
-        match runtime.execute_current_instruction::<EmptyContext>(None) {
-            Err(e) => {
-                error!("Failure during annotation execution: {:?}", e);
-                continue;
-            }
-            Ok(data) => match data.get_state() {
-                SimpleRuntimeState::Running => (),
-                SimpleRuntimeState::End => break,
-            },
-        }
-    }
-
-    let value_ref = match runtime.get_data().get_current_value() {
-        None => {
-            error!("No value after annotation execution. Expected value of type List.");
-            return Err("No value after execution".into());
-        }
-        Some(v) => v,
-    };
-
-    let (name, start) =
-        get_name_expression_annotation_parameters(runtime, value_ref).or(Err(String::new()))?;
-
-    Ok((parsed, instruction_data, name, start))
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_site_defines_take_priority_over_site_config_and_environment() {
+        let dir = std::env::temp_dir()
+            .join(format!("garnish-web-server-build-site-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("garnish-web.toml"),
+            "[script]\nenv_prefix = \"GARNISH_WEB_BUILD_SITE_TEST_\"\nexpose = [\"*\"]\n\n\
+             [script.values]\nfrom_file = \"file\"\nfrom_env = \"file\"\nfrom_define = \"file\"\n",
+        )
+        .unwrap();
+        std::env::set_var("GARNISH_WEB_BUILD_SITE_TEST_FROM_ENV", "env");
+        std::env::set_var("GARNISH_WEB_BUILD_SITE_TEST_FROM_DEFINE", "env");
+
+        let args = ServerArgs::parse_from([
+            "garnish-web-server",
+            "--define",
+            "from_define=define",
+            "serve",
+        ]);
+        let state = build_site(&dir, &args).unwrap();
+        let values = state.context().values();
 
-fn get_name_expression_annotation_parameters(
-    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
-    value_ref: usize,
-) -> Result<(String, usize), ()> {
-    match runtime.get_data().get_data_type(value_ref) {
-        Err(_) => {
-            error!("Failed to retrieve value data type after annotation execution.");
-            Err(())
-        }
-        Ok(t) => match t {
-            GarnishDataType::List => {
-                // check for 2 values in list
-                let method_name = match runtime.get_data().get_list_item(value_ref, 0.into()) {
-                    Err(e) => {
-                        error!(
-                            "Failed to retrieve list item 0 for annotation list value. {:?}",
-                            e
-                        );
-                        return Err(());
-                    }
-                    Ok(v) => match runtime.get_data().get_data_type(v) {
-                        Err(_) => {
-                            error!("Failed to retrieve value data type for annotation list value.");
-                            return Err(());
-                        }
-                        Ok(t) => match t {
-                            GarnishDataType::Symbol => {
-                                match runtime.get_data().get_symbol(v) {
-                                    Err(_) => {
-                                        error!("No data found for annotation list value item 0");
-                                        return Err(());
-                                    }
-                                    Ok(s) => match runtime.get_data().get_symbols().get(&s) {
-                                        None => {
-                                            error!("Symbol with value {} not found in data symbol table", s);
-                                            return Err(());
-                                        }
-                                        Some(s) => s.clone(),
-                                    },
-                                }
-                            }
-                            GarnishDataType::CharList => {
-                                match runtime.get_data().get_data().get(v) {
-                                    None => {
-                                        error!("No data found for annotation list value item 0");
-                                        return Err(());
-                                    }
-                                    Some(s) => match s.as_char_list() {
-                                        Err(e) => {
-                                            error!("Value stored in Character List slot {} could not be cast to Character List. {:?}", v, e);
-                                            return Err(());
-                                        }
-                                        Ok(s) => s,
-                                    },
-                                }
-                            }
-                            _ => {
-                                error!("Expected Character List or Symbol type as first parameter in annotation list value");
-                                return Err(());
-                            }
-                        },
-                    },
-                };
-
-                let execution_start = match runtime.get_data().get_list_item(value_ref, 1.into()) {
-                    Err(e) => {
-                        error!(
-                            "Failed to retrieve list item 1 for annotation list value. {:?}",
-                            e
-                        );
-                        return Err(());
-                    }
-                    Ok(v) => match runtime.get_data().get_data_type(v) {
-                        Err(_) => {
-                            error!("Failed to retrieve value data type for annotation list value.");
-                            return Err(());
-                        }
-                        Ok(t) => match t {
-                            GarnishDataType::Expression => {
-                                match runtime.get_data().get_expression(v) {
-                                    Err(_) => {
-                                        error!("No data found for annotation list value item 0");
-                                        return Err(());
-                                    }
-                                    Ok(s) => s,
-                                }
-                            }
-                            _ => {
-                                error!("Expected Expression type as second parameter in annotation list value");
-                                return Err(());
-                            }
-                        },
-                    },
-                };
-
-                Ok((method_name, execution_start))
-            }
-            t => {
-                warn!(
-                    "Expected List data type after annotation execution. Found {:?}",
-                    t
-                );
-                Err(())
-            }
-        },
+        assert_eq!(values.get("from_file"), Some(&ScriptValue::Text(String::from("file"))));
+        assert_eq!(values.get("from_env"), Some(&ScriptValue::Text(String::from("env"))));
+        assert_eq!(values.get("from_define"), Some(&ScriptValue::Text(String::from("define"))));
     }
 }
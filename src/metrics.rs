@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use log::info;
+
+use crate::config::MetricsConfig;
+
+const CONTENT_TYPE_PROMETHEUS: &str = "text/plain; version=0.0.4";
+
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+const INSTRUCTION_BUCKETS: [f64; 7] = [
+    100.0, 1_000.0, 10_000.0, 100_000.0, 1_000_000.0, 10_000_000.0, 100_000_000.0,
+];
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.buckets.iter().position(|b| value <= *b) {
+            self.counts[i] += 1;
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Site label and route key.
+type RouteLabel = (String, String);
+
+#[derive(Debug, Default)]
+struct MetricData {
+    requests: BTreeMap<(RouteLabel, u16), u64>,
+    latency: BTreeMap<RouteLabel, Histogram>,
+    instructions: BTreeMap<RouteLabel, Histogram>,
+    runtime_errors: BTreeMap<RouteLabel, u64>,
+    deserialize_failures: BTreeMap<(RouteLabel, &'static str), u64>,
+    compiles: BTreeMap<&'static str, u64>,
+    reloads: u64,
+}
+
+/// Counters and histograms of the whole process, exposed in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    data: Mutex<MetricData>,
+}
+
+/// Metrics shared by every site served by the process.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+    /// Records a finished request. Site is the mount prefix or host pattern of the site that served it,
+    /// empty when serving a single site. Route is the matched route key, or empty when no route matched.
+    pub fn record_request(&self, site: &str, route: &str, status: u16, duration: Duration) {
+        let mut data = self.data.lock().unwrap();
+
+        *data.requests.entry((label(site, route), status)).or_default() += 1;
+        data.latency
+            .entry(label(site, route))
+            .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn record_instructions(&self, site: &str, route: &str, instructions: usize) {
+        self.data
+            .lock()
+            .unwrap()
+            .instructions
+            .entry(label(site, route))
+            .or_insert_with(|| Histogram::new(&INSTRUCTION_BUCKETS))
+            .observe(instructions as f64);
+    }
+
+    pub fn record_runtime_error(&self, site: &str, route: &str) {
+        *self
+            .data
+            .lock()
+            .unwrap()
+            .runtime_errors
+            .entry(label(site, route))
+            .or_default() += 1;
+    }
+
+    /// Records a route's result that couldn't be read as the given kind of output.
+    pub fn record_deserialize_failure(&self, site: &str, route: &str, kind: &'static str) {
+        *self
+            .data
+            .lock()
+            .unwrap()
+            .deserialize_failures
+            .entry((label(site, route), kind))
+            .or_default() += 1;
+    }
+
+    /// Records compiling one source file.
+    pub fn record_compile(&self, success: bool) {
+        let result = match success {
+            true => "success",
+            false => "failure",
+        };
+
+        *self.data.lock().unwrap().compiles.entry(result).or_default() += 1;
+    }
+
+    /// Records replacing the served build while running.
+    pub fn record_reload(&self) {
+        self.data.lock().unwrap().reloads += 1;
+    }
+
+    /// All metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let data = self.data.lock().unwrap();
+        let mut output = String::new();
+
+        write_header(
+            &mut output,
+            "garnish_web_requests_total",
+            "counter",
+            "Requests by site, matched route key and status.",
+        );
+        for (((site, route), status), count) in data.requests.iter() {
+            output.push_str(&format!(
+                "garnish_web_requests_total{{site=\"{}\",route=\"{}\",status=\"{}\"}} {}\n",
+                escape_label(site),
+                escape_label(route),
+                status,
+                count
+            ));
+        }
+
+        write_histograms(
+            &mut output,
+            "garnish_web_request_duration_seconds",
+            "Time to respond to requests by site and matched route key.",
+            &data.latency,
+        );
+
+        write_histograms(
+            &mut output,
+            "garnish_web_instructions_executed",
+            "Garnish instructions executed per request by site and route key.",
+            &data.instructions,
+        );
+
+        write_header(
+            &mut output,
+            "garnish_web_runtime_errors_total",
+            "counter",
+            "Routes that failed while executing, by site.",
+        );
+        for ((site, route), count) in data.runtime_errors.iter() {
+            output.push_str(&format!(
+                "garnish_web_runtime_errors_total{{site=\"{}\",route=\"{}\"}} {}\n",
+                escape_label(site),
+                escape_label(route),
+                count
+            ));
+        }
+
+        write_header(
+            &mut output,
+            "garnish_web_deserialize_failures_total",
+            "counter",
+            "Route results that couldn't be rendered, by site and output type.",
+        );
+        for (((site, route), kind), count) in data.deserialize_failures.iter() {
+            output.push_str(&format!(
+                "garnish_web_deserialize_failures_total{{site=\"{}\",route=\"{}\",type=\"{}\"}} {}\n",
+                escape_label(site),
+                escape_label(route),
+                kind,
+                count
+            ));
+        }
+
+        write_counters(
+            &mut output,
+            "garnish_web_compiles_total",
+            "Source files compiled, by result.",
+            "result",
+            data.compiles.iter().map(|(k, v)| (*k, *v)),
+        );
+
+        write_header(
+            &mut output,
+            "garnish_web_reloads_total",
+            "counter",
+            "Builds replaced while running.",
+        );
+        output.push_str(&format!("garnish_web_reloads_total {}\n", data.reloads));
+
+        output
+    }
+}
+
+fn write_header(output: &mut String, name: &str, kind: &str, help: &str) {
+    output.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+}
+
+fn write_counters<'a, I: Iterator<Item = (&'a str, u64)>>(
+    output: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: I,
+) {
+    write_header(output, name, "counter", help);
+
+    for (value, count) in values {
+        output.push_str(&format!(
+            "{}{{{}=\"{}\"}} {}\n",
+            name,
+            label,
+            escape_label(value),
+            count
+        ));
+    }
+}
+
+fn write_histograms(
+    output: &mut String,
+    name: &str,
+    help: &str,
+    histograms: &BTreeMap<RouteLabel, Histogram>,
+) {
+    write_header(output, name, "histogram", help);
+
+    for ((site, route), histogram) in histograms.iter() {
+        let labels = format!("site=\"{}\",route=\"{}\"", escape_label(site), escape_label(route));
+        let mut cumulative = 0;
+
+        for (bucket, count) in histogram.buckets.iter().zip(histogram.counts.iter()) {
+            cumulative += count;
+            output.push_str(&format!(
+                "{}_bucket{{{},le=\"{}\"}} {}\n",
+                name, labels, bucket, cumulative
+            ));
+        }
+
+        output.push_str(&format!(
+            "{}_bucket{{{},le=\"+Inf\"}} {}\n",
+            name, labels, histogram.count
+        ));
+        output.push_str(&format!("{}_sum{{{}}} {}\n", name, labels, histogram.sum));
+        output.push_str(&format!("{}_count{{{}}} {}\n", name, labels, histogram.count));
+    }
+}
+
+fn label(site: &str, route: &str) -> RouteLabel {
+    (site.to_string(), route.to_string())
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+async fn metrics_handler() -> impl IntoResponse {
+    ([(CONTENT_TYPE, CONTENT_TYPE_PROMETHEUS)], metrics().render())
+}
+
+/// Adds the metrics endpoint in front of the app's routes, when enabled and not served on its own address.
+pub fn with_metrics(app: Router, config: &MetricsConfig) -> Router {
+    match (config.enabled, &config.bind) {
+        (true, None) => Router::new()
+            .route(
+                &format!("/{}", config.path.trim_start_matches('/')),
+                get(metrics_handler),
+            )
+            .merge(app),
+        _ => app,
+    }
+}
+
+/// Serves only the metrics endpoint on its own address, so it can be kept off the public listener.
+/// Stops accepting connections once the stopped future resolves.
+pub async fn serve_metrics<F: Future<Output = ()>>(
+    bind: &str,
+    path: &str,
+    stopped: F,
+) -> Result<(), String> {
+    let address: SocketAddr = bind
+        .parse()
+        .or_else(|e| Err(format!("Invalid metrics bind address {:?}. Reason: {}", bind, e)))?;
+
+    let app = Router::new().route(
+        &format!("/{}", path.trim_start_matches('/')),
+        get(metrics_handler),
+    );
+
+    info!("Serving metrics on {}", address);
+    axum::Server::try_bind(&address)
+        .or_else(|e| Err(format!("Failed to bind {}. Reason: {}", address, e)))?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(stopped)
+        .await
+        .or_else(|e| Err(format!("Server error. Reason: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_are_labelled_with_their_site() {
+        let metrics = Metrics::default();
+        metrics.record_request("/docs", "index", 200, Duration::from_millis(2));
+        metrics.record_request("", "index", 404, Duration::from_millis(2));
+        metrics.record_runtime_error("blog.example.com", "post");
+        metrics.record_deserialize_failure("/docs", "style", "css");
+
+        let output = metrics.render();
+
+        assert!(output.contains(
+            "garnish_web_requests_total{site=\"/docs\",route=\"index\",status=\"200\"} 1\n"
+        ));
+        assert!(output
+            .contains("garnish_web_requests_total{site=\"\",route=\"index\",status=\"404\"} 1\n"));
+        assert!(output.contains(
+            "garnish_web_request_duration_seconds_bucket{site=\"/docs\",route=\"index\",le=\"0.0025\"} 1\n"
+        ));
+        assert!(output.contains(
+            "garnish_web_runtime_errors_total{site=\"blog.example.com\",route=\"post\"} 1\n"
+        ));
+        assert!(output.contains(
+            "garnish_web_deserialize_failures_total{site=\"/docs\",route=\"style\",type=\"css\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let metrics = Metrics::default();
+        metrics.record_runtime_error("", "say \"hi\"\\\n");
+
+        assert!(metrics
+            .render()
+            .contains("route=\"say \\\"hi\\\"\\\\\\n\"} 1\n"));
+    }
+}
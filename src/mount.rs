@@ -0,0 +1,151 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::Request;
+use axum::response::{IntoResponse, Response};
+use axum::routing::any;
+use axum::Router;
+use hyper::StatusCode;
+use log::info;
+
+use crate::{rewrite_request, serve_request, SharedState, SiteKey};
+
+/// Separately compiled sites served from one router, each under its own URL prefix.
+#[derive(Clone, Default)]
+pub struct MountTable {
+    // kept sorted by longest prefix first
+    mounts: Vec<(String, Arc<SharedState>)>,
+}
+
+impl MountTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves a site under the prefix. Replaces any site already mounted at the same prefix.
+    pub fn mount<T: AsRef<str>>(&mut self, prefix: T, state: Arc<SharedState>) {
+        let prefix = normalize_prefix(prefix.as_ref());
+
+        info!("Mounting site at \"/{}\"", prefix);
+
+        self.mounts.retain(|(p, _)| p != &prefix);
+        self.mounts.push((prefix, state));
+        self.mounts.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    }
+
+    pub fn mounts(&self) -> &Vec<(String, Arc<SharedState>)> {
+        &self.mounts
+    }
+
+    /// Finds the site with the longest prefix matching the path.
+    /// Returns the prefix, the site and the path with the prefix removed.
+    pub fn find<'a>(&'a self, path: &str) -> Option<(&'a String, &'a Arc<SharedState>, String)> {
+        let path = path.trim_matches('/');
+
+        self.mounts.iter().find_map(|(prefix, state)| {
+            if prefix.is_empty() {
+                return Some((prefix, state, path.to_string()));
+            }
+
+            match path.strip_prefix(prefix.as_str()) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                    Some((prefix, state, rest.trim_start_matches('/').to_string()))
+                }
+                _ => None,
+            }
+        })
+    }
+}
+
+/// Parses a '/prefix=path' command line argument.
+pub fn parse_mount(text: &str) -> Result<(String, PathBuf), String> {
+    match text.split_once('=') {
+        Some((prefix, path)) if !path.trim().is_empty() => {
+            Ok((prefix.trim().to_string(), PathBuf::from(path.trim())))
+        }
+        _ => Err(format!("Expected mount in form '/prefix=path'. Found {:?}", text)),
+    }
+}
+
+fn normalize_prefix(prefix: &str) -> String {
+    prefix.trim().trim_matches('/').to_string()
+}
+
+/// Creates a router that sends each request to the site with the longest matching prefix.
+pub fn create_mount_router(table: MountTable) -> Router {
+    Router::new()
+        .route("/", any(mount_handler))
+        .route("/*path", any(mount_handler))
+        .with_state(Arc::new(table))
+}
+
+pub async fn mount_handler(
+    State(table): State<Arc<MountTable>>,
+    request: Request<Body>,
+) -> Response {
+    serve_mounted(&table, "", request).await
+}
+
+/// Serves the request from the mounted site with the longest prefix matching its path.
+/// Requests are labelled in metrics and logs with the site name and mount prefix.
+pub(crate) async fn serve_mounted(
+    table: &MountTable,
+    site: &str,
+    mut request: Request<Body>,
+) -> Response {
+    let (prefix, state, path) = match table.find(request.uri().path()) {
+        None => {
+            info!("No mount found for path \"{}\"", request.uri().path());
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Some(v) => v,
+    };
+
+    rewrite_request(&mut request, &path);
+    request.extensions_mut().insert(SiteKey(mount_name(site, prefix)));
+
+    serve_request(State(state.clone()), request).await
+}
+
+/// Name of a mounted site, its prefix after the name of the site it's mounted in.
+/// A host's root mount is named after the host alone.
+pub(crate) fn mount_name(site: &str, prefix: &str) -> String {
+    match (site.is_empty(), prefix.is_empty()) {
+        (false, true) => site.to_string(),
+        _ => format!("{}/{}", site, prefix),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mount_splits_prefix_and_path() {
+        assert_eq!(
+            parse_mount(" /docs = ../docs "),
+            Ok((String::from("/docs"), PathBuf::from("../docs")))
+        );
+    }
+
+    #[test]
+    fn parse_mount_allows_root_prefix() {
+        assert_eq!(parse_mount("/=site"), Ok((String::from("/"), PathBuf::from("site"))));
+    }
+
+    #[test]
+    fn mount_name_adds_prefix_to_site() {
+        assert_eq!(mount_name("", ""), "/");
+        assert_eq!(mount_name("", "docs"), "/docs");
+        assert_eq!(mount_name("example.com", ""), "example.com");
+        assert_eq!(mount_name("example.com", "docs"), "example.com/docs");
+    }
+
+    #[test]
+    fn parse_mount_requires_path() {
+        assert!(parse_mount("/docs").is_err());
+        assert!(parse_mount("/docs= ").is_err());
+    }
+}
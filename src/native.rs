@@ -0,0 +1,53 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+use garnish_lang::simple::{DataError, SimpleGarnishData};
+use garnish_lang::RuntimeError;
+
+/// Signature for functions implemented in Rust.
+/// Receives the address of the input value and returns the address of the result, after adding it to the data.
+pub type NativeFn =
+    dyn Fn(usize, &mut SimpleGarnishData) -> Result<usize, RuntimeError<DataError>> + Send + Sync;
+
+/// Named Rust function callable from garnish scripts.
+#[derive(Clone)]
+pub struct NativeFunction {
+    name: String,
+    function: Arc<NativeFn>,
+}
+
+impl NativeFunction {
+    pub fn new<T, F>(name: T, function: F) -> Self
+    where
+        T: Into<String>,
+        F: Fn(usize, &mut SimpleGarnishData) -> Result<usize, RuntimeError<DataError>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self {
+            name: name.into(),
+            function: Arc::new(function),
+        }
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn call(
+        &self,
+        input_addr: usize,
+        data: &mut SimpleGarnishData,
+    ) -> Result<usize, RuntimeError<DataError>> {
+        (self.function)(input_addr, data)
+    }
+}
+
+impl Debug for NativeFunction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name)
+            .finish()
+    }
+}
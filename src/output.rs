@@ -0,0 +1,464 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use axum::body::Body;
+use axum::http::Request;
+use hypertext_garnish::{Node, RuleSet};
+use serde::{Deserialize, Serialize};
+
+use crate::config::OutputConfig;
+
+/// Query parameter choosing the output mode of a single request, when enabled.
+pub const OUTPUT_QUERY_PARAMETER: &str = "output";
+
+const INDENT: &str = "  ";
+
+/// Elements whose text is rendered exactly as written.
+const PREFORMATTED_TAGS: [&str; 4] = ["pre", "textarea", "script", "style"];
+
+/// How rendered HTML and CSS is formatted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    /// Output as produced by the script, without added whitespace.
+    #[default]
+    Compact,
+    /// Compact output with HTML comments removed, runs of whitespace in text collapsed,
+    /// and empty CSS rules and final semicolons of CSS rules removed.
+    Minified,
+    /// Indented output for reading during development.
+    Pretty,
+}
+
+impl Display for OutputMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OutputMode::Compact => "compact",
+            OutputMode::Minified => "minified",
+            OutputMode::Pretty => "pretty",
+        })
+    }
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "compact" => Ok(OutputMode::Compact),
+            "minified" | "minify" => Ok(OutputMode::Minified),
+            "pretty" => Ok(OutputMode::Pretty),
+            _ => Err(format!(
+                "Expected output mode of compact, minified or pretty. Found {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// Output mode for a request. The query parameter is only used when the config allows it.
+pub fn request_output_mode(config: &OutputConfig, request: &Request<Body>) -> OutputMode {
+    if !config.query_override {
+        return config.mode;
+    }
+
+    request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == OUTPUT_QUERY_PARAMETER)
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(config.mode)
+}
+
+/// Renders HTML in the output mode. Minified and pretty output are made from the compact output.
+pub fn render_html(node: &Node, mode: OutputMode) -> String {
+    let html = node.to_string();
+
+    match mode {
+        OutputMode::Compact => html,
+        OutputMode::Minified => minify_html(&html),
+        OutputMode::Pretty => pretty_html(&html),
+    }
+}
+
+/// Renders CSS in the output mode. Minified and pretty output are made from the compact output.
+pub fn render_css(rules: &RuleSet, mode: OutputMode) -> String {
+    let css = rules.to_string();
+
+    match mode {
+        OutputMode::Compact => css,
+        OutputMode::Minified => minify_css(&css),
+        OutputMode::Pretty => pretty_css(&css),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HtmlToken<'a> {
+    Open(&'a str, &'a str),
+    Close(&'a str),
+    Comment(&'a str),
+    /// Doctype and other declarations, which have no closing tag.
+    Declaration(&'a str),
+    Text(&'a str),
+    /// Content of a preformatted element, rendered exactly as written.
+    Preformatted(&'a str),
+}
+
+/// Splits compact HTML into tags, comments and text. Every element in compact output has a closing tag.
+fn html_tokens(html: &str) -> Vec<HtmlToken<'_>> {
+    let mut tokens = vec![];
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        if !rest.starts_with('<') {
+            let end = rest.find('<').unwrap_or(rest.len());
+            tokens.push(HtmlToken::Text(&rest[..end]));
+            rest = &rest[end..];
+            continue;
+        }
+
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").map(|i| i + 3).unwrap_or(rest.len());
+            tokens.push(HtmlToken::Comment(&rest[..end]));
+            rest = &rest[end..];
+            continue;
+        }
+
+        let end = tag_end(rest);
+        let tag = &rest[..end];
+        rest = &rest[end..];
+
+        if tag.starts_with("</") {
+            tokens.push(HtmlToken::Close(tag));
+        } else if tag.starts_with("<!") {
+            tokens.push(HtmlToken::Declaration(tag));
+        } else {
+            let name = tag[1..]
+                .split(|c: char| c.is_whitespace() || c == '>')
+                .next()
+                .unwrap_or_default();
+            tokens.push(HtmlToken::Open(tag, name));
+
+            if PREFORMATTED_TAGS.contains(&name.to_lowercase().as_str()) {
+                let end = rest
+                    .to_lowercase()
+                    .find(&format!("</{}>", name.to_lowercase()))
+                    .unwrap_or(rest.len());
+                if end > 0 {
+                    tokens.push(HtmlToken::Preformatted(&rest[..end]));
+                }
+                rest = &rest[end..];
+            }
+        }
+    }
+
+    tokens
+}
+
+// attribute values may contain '>' themselves
+fn tag_end(text: &str) -> usize {
+    let mut quote = None;
+
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '>' => return i + 1,
+            None => (),
+        }
+    }
+
+    text.len()
+}
+
+fn minify_html(html: &str) -> String {
+    html_tokens(html)
+        .into_iter()
+        .map(|token| match token {
+            HtmlToken::Comment(_) => String::new(),
+            HtmlToken::Text(text) => collapse_whitespace(text),
+            HtmlToken::Open(tag, _)
+            | HtmlToken::Close(tag)
+            | HtmlToken::Declaration(tag)
+            | HtmlToken::Preformatted(tag) => tag.to_string(),
+        })
+        .collect()
+}
+
+// keeps a single space at either end, since it separates the text from neighboring inline elements
+fn collapse_whitespace(text: &str) -> String {
+    let words = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+    if words.is_empty() {
+        return match text.is_empty() {
+            true => String::new(),
+            false => String::from(" "),
+        };
+    }
+
+    let start = match text.starts_with(char::is_whitespace) {
+        true => " ",
+        false => "",
+    };
+    let end = match text.ends_with(char::is_whitespace) {
+        true => " ",
+        false => "",
+    };
+
+    format!("{}{}{}", start, words, end)
+}
+
+/// Indents compact HTML, putting each element on its own line.
+/// Elements containing only text stay on one line.
+fn pretty_html(html: &str) -> String {
+    let tokens = html_tokens(html);
+    let mut output = String::new();
+    let mut depth: usize = 0;
+    let mut i = 0;
+
+    while i < tokens.len() {
+        let indent = INDENT.repeat(depth);
+
+        match tokens[i] {
+            HtmlToken::Open(tag, _) => match (tokens.get(i + 1), tokens.get(i + 2)) {
+                (Some(HtmlToken::Close(close)), _) => {
+                    output.push_str(&format!("{}{}{}\n", indent, tag, close));
+                    i += 1;
+                }
+                (Some(HtmlToken::Text(text)), Some(HtmlToken::Close(close))) => {
+                    let words = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+                    output.push_str(&format!("{}{}{}{}\n", indent, tag, words, close));
+                    i += 2;
+                }
+                (Some(HtmlToken::Preformatted(text)), Some(HtmlToken::Close(close))) => {
+                    output.push_str(&format!("{}{}{}{}\n", indent, tag, text, close));
+                    i += 2;
+                }
+                _ => {
+                    output.push_str(&format!("{}{}\n", indent, tag));
+                    depth += 1;
+                }
+            },
+            HtmlToken::Close(tag) => {
+                depth = depth.saturating_sub(1);
+                output.push_str(&format!("{}{}\n", INDENT.repeat(depth), tag));
+            }
+            HtmlToken::Text(text) => {
+                let words = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+                if !words.is_empty() {
+                    output.push_str(&format!("{}{}\n", indent, words));
+                }
+            }
+            HtmlToken::Comment(text)
+            | HtmlToken::Declaration(text)
+            | HtmlToken::Preformatted(text) => {
+                output.push_str(&format!("{}{}\n", indent, text));
+            }
+        }
+
+        i += 1;
+    }
+
+    output
+}
+
+/// Removes whitespace around punctuation, the final semicolon of each rule and rules without declarations.
+/// Whitespace before ':' is kept, since it separates a pseudo class from a descendant selector.
+fn minify_css(css: &str) -> String {
+    let mut output = String::new();
+    let mut quote = None;
+    let mut space = false;
+
+    for c in css.chars() {
+        if let Some(q) = quote {
+            output.push(c);
+            if c == q {
+                quote = None;
+            }
+            continue;
+        }
+
+        if c.is_whitespace() {
+            space = true;
+            continue;
+        }
+
+        let punctuation = matches!(c, '{' | '}' | ';' | ',' | '>');
+        if space
+            && !punctuation
+            && !output.is_empty()
+            && !output.ends_with(['{', '}', ';', ',', '>', ':'])
+        {
+            output.push(' ');
+        }
+        space = false;
+
+        match c {
+            '"' | '\'' => {
+                quote = Some(c);
+                output.push(c);
+            }
+            '}' => {
+                if output.ends_with(';') {
+                    output.pop();
+                }
+
+                match output.ends_with('{') {
+                    // drops the empty rule along with its selector
+                    true => {
+                        output.pop();
+                        let start = output.rfind(['{', '}', ';']).map(|i| i + 1).unwrap_or(0);
+                        output.truncate(start);
+                    }
+                    false => output.push(c),
+                }
+            }
+            _ => output.push(c),
+        }
+    }
+
+    output
+}
+
+/// Indents compact CSS, putting each declaration on its own line.
+fn pretty_css(css: &str) -> String {
+    let mut output = String::new();
+    let mut segment = String::new();
+    let mut depth = 0;
+    let mut quote = None;
+
+    for c in css.chars() {
+        if let Some(q) = quote {
+            segment.push(c);
+            if c == q {
+                quote = None;
+            }
+            continue;
+        }
+
+        match c {
+            '"' | '\'' => {
+                quote = Some(c);
+                segment.push(c);
+            }
+            '{' => {
+                output.push_str(&format!(
+                    "{}{} {{\n",
+                    INDENT.repeat(depth),
+                    segment.trim().replace(",", ", ")
+                ));
+                segment.clear();
+                depth += 1;
+            }
+            ';' => {
+                let declaration = match segment.trim().split_once(':') {
+                    Some((property, value)) => format!("{}: {}", property, value),
+                    None => segment.trim().to_string(),
+                };
+                output.push_str(&format!("{}{};\n", INDENT.repeat(depth), declaration));
+                segment.clear();
+            }
+            '}' => {
+                depth = depth.saturating_sub(1);
+                output.push_str(&format!("{}}}\n", INDENT.repeat(depth)));
+                segment.clear();
+            }
+            _ => segment.push(c),
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use hypertext_garnish::{Attribute, Declaration, DeclarationValue, Rule, Selector};
+
+    use super::*;
+
+    fn page() -> Node {
+        Node::element(
+            String::from("body"),
+            vec![],
+            vec![
+                Node::comment(String::from("navigation")),
+                Node::element(
+                    String::from("p"),
+                    vec![Attribute::new(String::from("title"), String::from("a > b"))],
+                    vec![Node::text(String::from("  Hello \n  world  "))],
+                ),
+                Node::element(
+                    String::from("pre"),
+                    vec![],
+                    vec![Node::text(String::from("  keep\n  this"))],
+                ),
+            ],
+        )
+    }
+
+    fn rules() -> RuleSet {
+        let declaration = |property: &str, value: &str| {
+            Declaration::new(
+                String::from(property),
+                DeclarationValue::Basic(String::from(value)),
+            )
+        };
+
+        RuleSet::new(
+            vec![
+                Rule::new(
+                    Selector::Tag(String::from("p")),
+                    vec![declaration("color", "red"), declaration("margin", "0")],
+                    vec![],
+                ),
+                Rule::new(Selector::Class(String::from("empty")), vec![], vec![]),
+            ],
+            vec![],
+            None,
+        )
+    }
+
+    #[test]
+    fn minified_html_drops_comments_and_collapses_text() {
+        assert_eq!(
+            render_html(&page(), OutputMode::Minified),
+            "<body><p title=\"a > b\"> Hello world </p><pre>  keep\n  this</pre></body>"
+        );
+    }
+
+    #[test]
+    fn pretty_html_indents_elements() {
+        assert_eq!(
+            render_html(&page(), OutputMode::Pretty),
+            "<body>\n  <!-- navigation -->\n  <p title=\"a > b\">Hello world</p>\n  <pre>  keep\n  this</pre>\n</body>\n"
+        );
+    }
+
+    #[test]
+    fn compact_output_is_unchanged() {
+        assert_eq!(
+            render_html(&page(), OutputMode::Compact),
+            page().to_string()
+        );
+        assert_eq!(
+            render_css(&rules(), OutputMode::Compact),
+            rules().to_string()
+        );
+    }
+
+    #[test]
+    fn minified_css_drops_final_semicolons_and_empty_rules() {
+        assert_eq!(
+            render_css(&rules(), OutputMode::Minified),
+            "p{color:red;margin:0}"
+        );
+        assert_eq!(
+            minify_css("@media screen { a > b { } }c , d { x: \"a  b\" ; }"),
+            "c,d{x:\"a  b\"}"
+        );
+    }
+}
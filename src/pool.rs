@@ -0,0 +1,131 @@
+use std::sync::Mutex;
+
+use garnish_lang::simple::{SimpleGarnishData, SimpleGarnishRuntime};
+use garnish_lang::{GarnishData, GarnishRuntime};
+
+/// Most runtimes kept idle between requests.
+pub const POOL_MAX_IDLE: usize = 64;
+
+/// Runtimes reused across requests, so the compiled instructions and data are only copied when none are idle.
+///
+/// Returned runtimes are reset to the compiled data before another request can take them. Garnish data can only be
+/// appended to and caches the addresses of added values, so a runtime that added values can't be truncated back to
+/// its end of constant data and has its data restored from the base instead.
+pub struct RuntimePool {
+    idle: Mutex<Vec<SimpleGarnishRuntime<SimpleGarnishData>>>,
+    base_data_len: usize,
+}
+
+impl RuntimePool {
+    pub fn new(base: &SimpleGarnishRuntime<SimpleGarnishData>) -> Self {
+        Self {
+            idle: Mutex::new(vec![]),
+            base_data_len: base.get_data().get_data_len(),
+        }
+    }
+
+    /// An idle runtime, or a copy of the base when none are idle.
+    pub fn take(
+        &self,
+        base: &SimpleGarnishRuntime<SimpleGarnishData>,
+    ) -> SimpleGarnishRuntime<SimpleGarnishData> {
+        match self.idle.lock().unwrap().pop() {
+            Some(runtime) => runtime,
+            None => base.clone(),
+        }
+    }
+
+    /// Resets a runtime to the compiled data of the base and makes it available to later requests.
+    pub fn put(
+        &self,
+        mut runtime: SimpleGarnishRuntime<SimpleGarnishData>,
+        base: &SimpleGarnishRuntime<SimpleGarnishData>,
+    ) {
+        if self.idle.lock().unwrap().len() >= POOL_MAX_IDLE {
+            return;
+        }
+
+        let data = runtime.get_data_mut();
+
+        if data.get_data_len() == self.base_data_len {
+            while data.pop_value_stack().is_some() {}
+            while data.pop_register().is_some() {}
+            while data.pop_jump_path().is_some() {}
+        } else {
+            *data = base.get_data().clone();
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < POOL_MAX_IDLE {
+            idle.push(runtime);
+        }
+    }
+
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use garnish_lang::simple::SimpleNumber;
+
+    use super::*;
+
+    fn base() -> SimpleGarnishRuntime<SimpleGarnishData> {
+        let mut data = SimpleGarnishData::new();
+        data.add_number(SimpleNumber::Integer(10)).unwrap();
+        SimpleGarnishRuntime::new(data)
+    }
+
+    #[test]
+    fn returned_runtimes_are_reused() {
+        let base = base();
+        let pool = RuntimePool::new(&base);
+
+        let runtime = pool.take(&base);
+        assert_eq!(pool.idle(), 0);
+
+        pool.put(runtime, &base);
+        assert_eq!(pool.idle(), 1);
+
+        pool.take(&base);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn values_added_by_one_request_are_not_seen_by_the_next() {
+        let base = base();
+        let pool = RuntimePool::new(&base);
+
+        let mut first = pool.take(&base);
+        let data = first.get_data_mut();
+        data.start_char_list().unwrap();
+        for c in "secret".chars() {
+            data.add_to_char_list(c).unwrap();
+        }
+        let secret = data.end_char_list().unwrap();
+        data.push_register(secret).unwrap();
+        data.push_value_stack(secret).unwrap();
+        pool.put(first, &base);
+
+        let second = pool.take(&base);
+        let data = second.get_data();
+        assert_eq!(data.get_data_len(), base.get_data().get_data_len());
+        assert_eq!(data.get_registers(), &Vec::<usize>::new());
+        assert_eq!(data.get_value_stack_len(), 0);
+        assert!(data.get_data_type(secret).is_err());
+    }
+
+    #[test]
+    fn idle_runtimes_are_bounded() {
+        let base = base();
+        let pool = RuntimePool::new(&base);
+
+        for _ in 0..POOL_MAX_IDLE + 1 {
+            pool.put(base.clone(), &base);
+        }
+
+        assert_eq!(pool.idle(), POOL_MAX_IDLE);
+    }
+}
@@ -0,0 +1,279 @@
+use std::path::PathBuf;
+
+use garnish_lang::compiler::lex::TokenType;
+use garnish_lang::simple::{DataError, SimpleData, SimpleGarnishData, SimpleGarnishRuntime};
+use garnish_lang::{GarnishData, GarnishDataType, RuntimeError};
+use garnish_lang_annotations_collector::{Collector, PartBehavior, PartParser, Sink, TokenBlock};
+use log::debug;
+
+use crate::context::WebContext;
+use crate::handle_def_annotations;
+use crate::native::NativeFunction;
+
+pub const PRELUDE_NAME: &str = "prelude.garnish";
+const PRELUDE_SOURCE: &str = include_str!("prelude.garnish");
+
+/// Compiles the built-in @Def helpers and registers the built-in natives.
+/// Must run before user files are compiled so their definitions take precedence.
+pub fn load_prelude(
+    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
+    context: &mut WebContext,
+) -> Result<(), String> {
+    debug!("Compiling prelude");
+
+    let collector: Collector = Collector::new(vec![Sink::new("@Def").part(PartParser::new(
+        PartBehavior::UntilToken(TokenType::Subexpression),
+    ))]);
+
+    let blocks: Vec<TokenBlock> = collector.collect_tokens_from_input(PRELUDE_SOURCE)?;
+    let def_blocks = blocks
+        .into_iter()
+        .filter(|b| b.annotation_text() == &"@Def".to_string())
+        .collect::<Vec<TokenBlock>>();
+
+    let mut def_metadata =
+        handle_def_annotations(def_blocks, runtime, context, &PathBuf::from(PRELUDE_NAME))?;
+
+    context.metadata_mut().append(&mut def_metadata);
+
+    for native in prelude_natives() {
+        context.insert_native(native);
+    }
+
+    Ok(())
+}
+
+/// Built-in natives. Resolved only when no expression with the same name exists.
+pub fn prelude_natives() -> Vec<NativeFunction> {
+    vec![
+        NativeFunction::new("escape_html", |input, data| {
+            let text = read_string(data, input)?;
+            add_string(data, &escape_html(&text))
+        }),
+        NativeFunction::new("escape_url", |input, data| {
+            let text = read_string(data, input)?;
+            add_string(data, &escape_url(&text))
+        }),
+        NativeFunction::new("query_string", |input, data| {
+            let mut parts = vec![];
+
+            for item in read_list(data, input)? {
+                let (key, value) = data.get_pair(item)?;
+                parts.push(format!(
+                    "{}={}",
+                    escape_url(&read_string(data, key)?),
+                    escape_url(&read_string(data, value)?)
+                ));
+            }
+
+            add_string(data, &parts.join("&"))
+        }),
+        NativeFunction::new("uppercase", |input, data| {
+            let text = read_string(data, input)?;
+            add_string(data, &text.to_uppercase())
+        }),
+        NativeFunction::new("lowercase", |input, data| {
+            let text = read_string(data, input)?;
+            add_string(data, &text.to_lowercase())
+        }),
+        NativeFunction::new("trim", |input, data| {
+            let text = read_string(data, input)?;
+            add_string(data, text.trim())
+        }),
+        NativeFunction::new("replace", |input, data| {
+            let text = read_string(data, list_item(data, input, 0)?)?;
+            let from = read_string(data, list_item(data, input, 1)?)?;
+            let to = read_string(data, list_item(data, input, 2)?)?;
+            add_string(data, &text.replace(&from, &to))
+        }),
+        NativeFunction::new("split", |input, data| {
+            let text = read_string(data, list_item(data, input, 0)?)?;
+            let separator = read_string(data, list_item(data, input, 1)?)?;
+
+            let mut items = vec![];
+            for part in text.split(separator.as_str()) {
+                items.push(add_string(data, part)?);
+            }
+
+            data.start_list(items.len())?;
+            for item in items {
+                data.add_to_list(item, false)?;
+            }
+            Ok(data.end_list()?)
+        }),
+        NativeFunction::new("join", |input, data| {
+            let list = list_item(data, input, 0)?;
+            let separator = read_string(data, list_item(data, input, 1)?)?;
+
+            let mut parts = vec![];
+            for item in read_list(data, list)? {
+                parts.push(read_string(data, item)?);
+            }
+
+            add_string(data, &parts.join(separator.as_str()))
+        }),
+    ]
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// Percent encodes everything except unreserved characters.
+pub fn escape_url(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+
+    for b in text.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                result.push(b as char)
+            }
+            b => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    result
+}
+
+/// Reads text from character lists, characters, symbols, numbers and booleans.
+pub fn read_string(
+    data: &SimpleGarnishData,
+    addr: usize,
+) -> Result<String, RuntimeError<DataError>> {
+    match data.get_data().get(addr) {
+        None => Err(RuntimeError::new_message(format!(
+            "No data found at addr {}",
+            addr
+        ))),
+        Some(SimpleData::CharList(s)) => Ok(s.clone()),
+        Some(SimpleData::Char(c)) => Ok(c.to_string()),
+        Some(SimpleData::Symbol(s)) => Ok(data.get_symbols().get(s).cloned().unwrap_or_default()),
+        Some(SimpleData::Number(n)) => Ok(format!("{}", n)),
+        Some(SimpleData::True) => Ok(String::from("true")),
+        Some(SimpleData::False) => Ok(String::from("false")),
+        Some(SimpleData::Unit) => Ok(String::new()),
+        Some(d) => Err(RuntimeError::new_message(format!(
+            "Expected text value at addr {}. Found {:?}",
+            addr,
+            d.get_data_type()
+        ))),
+    }
+}
+
+pub fn add_string(
+    data: &mut SimpleGarnishData,
+    text: &str,
+) -> Result<usize, RuntimeError<DataError>> {
+    data.start_char_list()?;
+    for c in text.chars() {
+        data.add_to_char_list(c)?;
+    }
+    Ok(data.end_char_list()?)
+}
+
+pub fn read_list(
+    data: &SimpleGarnishData,
+    addr: usize,
+) -> Result<Vec<usize>, RuntimeError<DataError>> {
+    match data.get_data_type(addr)? {
+        GarnishDataType::List => {
+            let len = data.get_list_len(addr)?;
+            let mut items = vec![];
+            for i in 0..len {
+                items.push(data.get_list_item(addr, (i as i32).into())?);
+            }
+            Ok(items)
+        }
+        // single values are treated as a list of one
+        _ => Ok(vec![addr]),
+    }
+}
+
+fn list_item(
+    data: &SimpleGarnishData,
+    addr: usize,
+    index: i32,
+) -> Result<usize, RuntimeError<DataError>> {
+    Ok(data.get_list_item(addr, index.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, data: &mut SimpleGarnishData, input: usize) -> usize {
+        prelude_natives()
+            .into_iter()
+            .find(|n| n.name() == name)
+            .unwrap()
+            .call(input, data)
+            .unwrap()
+    }
+
+    fn add_list(data: &mut SimpleGarnishData, items: Vec<usize>) -> usize {
+        data.start_list(items.len()).unwrap();
+        for item in items {
+            data.add_to_list(item, false).unwrap();
+        }
+        data.end_list().unwrap()
+    }
+
+    #[test]
+    fn html_special_characters_are_escaped() {
+        assert_eq!(
+            escape_html("<a href=\"/?a=1&b='2'\">"),
+            "&lt;a href=&quot;/?a=1&amp;b=&#39;2&#39;&quot;&gt;"
+        );
+
+        let mut data = SimpleGarnishData::new();
+        let input = add_string(&mut data, "<script>").unwrap();
+        let result = call("escape_html", &mut data, input);
+        assert_eq!(read_string(&data, result).unwrap(), "&lt;script&gt;");
+    }
+
+    #[test]
+    fn query_strings_escape_keys_and_values() {
+        let mut data = SimpleGarnishData::new();
+        let key = add_string(&mut data, "q").unwrap();
+        let value = add_string(&mut data, "fish & chips").unwrap();
+        let pair = data.add_pair((key, value)).unwrap();
+        let page_key = add_string(&mut data, "page").unwrap();
+        let page = data.add_number(2.into()).unwrap();
+        let page_pair = data.add_pair((page_key, page)).unwrap();
+        let input = add_list(&mut data, vec![pair, page_pair]);
+
+        let result = call("query_string", &mut data, input);
+        assert_eq!(
+            read_string(&data, result).unwrap(),
+            "q=fish%20%26%20chips&page=2"
+        );
+    }
+
+    #[test]
+    fn split_and_join_round_trip() {
+        let mut data = SimpleGarnishData::new();
+        let text = add_string(&mut data, "a,b,c").unwrap();
+        let separator = add_string(&mut data, ",").unwrap();
+        let input = add_list(&mut data, vec![text, separator]);
+        let parts = call("split", &mut data, input);
+        assert_eq!(read_list(&data, parts).unwrap().len(), 3);
+
+        let separator = add_string(&mut data, " - ").unwrap();
+        let input = add_list(&mut data, vec![parts, separator]);
+        let joined = call("join", &mut data, input);
+        assert_eq!(read_string(&data, joined).unwrap(), "a - b - c");
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use hyper::StatusCode;
+use serde::Serialize;
+
+use crate::config::ProbeConfig;
+use crate::mount::{mount_name, MountTable};
+use crate::vhost::HostTable;
+use crate::watch::LiveState;
+use crate::{SharedState, SERVER_VERSION};
+
+/// Build status of one site, as reported by the info endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct SiteStatus {
+    /// Mount prefix or host pattern of the site. Empty when only one site is served.
+    pub name: String,
+    pub routes: usize,
+    /// When the current build was created, as an HTTP date.
+    pub built: String,
+    pub errors: Vec<String>,
+}
+
+impl SiteStatus {
+    pub fn new<T: Into<String>>(name: T, state: &SharedState) -> Self {
+        Self {
+            name: name.into(),
+            routes: state.route_mapping().len(),
+            built: httpdate::fmt_http_date(state.built()),
+            errors: state.build_errors().clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ServerInfo {
+    version: &'static str,
+    ready: bool,
+    sites: Vec<SiteStatus>,
+}
+
+/// Served sites whose status the probe endpoints report.
+pub trait ProbeTarget: Send + Sync + 'static {
+    fn sites(&self) -> Vec<SiteStatus>;
+}
+
+impl ProbeTarget for SharedState {
+    fn sites(&self) -> Vec<SiteStatus> {
+        vec![SiteStatus::new("", self)]
+    }
+}
+
+impl ProbeTarget for LiveState {
+    fn sites(&self) -> Vec<SiteStatus> {
+        vec![SiteStatus::new("", &self.current())]
+    }
+}
+
+impl ProbeTarget for MountTable {
+    fn sites(&self) -> Vec<SiteStatus> {
+        mounted_sites(self, "")
+    }
+}
+
+impl ProbeTarget for HostTable {
+    fn sites(&self) -> Vec<SiteStatus> {
+        self.hosts()
+            .iter()
+            .flat_map(|(pattern, mounts)| mounted_sites(mounts, &pattern.to_string()))
+            .collect()
+    }
+}
+
+fn mounted_sites(mounts: &MountTable, site: &str) -> Vec<SiteStatus> {
+    mounts
+        .mounts()
+        .iter()
+        .map(|(prefix, state)| SiteStatus::new(mount_name(site, prefix), state))
+        .collect()
+}
+
+/// Adds the probe endpoints in front of the app's routes, when enabled.
+pub fn with_probes<T: ProbeTarget>(app: Router, target: Arc<T>, config: &ProbeConfig) -> Router {
+    if !config.enabled {
+        return app;
+    }
+
+    Router::new()
+        .route(&probe_path(&config.health_path), get(health_handler))
+        .route(&probe_path(&config.ready_path), get(ready_handler::<T>))
+        .route(&probe_path(&config.info_path), get(info_handler::<T>))
+        .with_state(target)
+        .merge(app)
+}
+
+fn probe_path(path: &str) -> String {
+    format!("/{}", path.trim_start_matches('/'))
+}
+
+async fn health_handler() -> &'static str {
+    "ok"
+}
+
+async fn ready_handler<T: ProbeTarget>(State(target): State<Arc<T>>) -> Response {
+    let errors = target
+        .sites()
+        .into_iter()
+        .flat_map(|site| site.errors)
+        .collect::<Vec<String>>();
+
+    match errors.is_empty() {
+        true => "ready".into_response(),
+        false => (StatusCode::SERVICE_UNAVAILABLE, errors.join("\n")).into_response(),
+    }
+}
+
+async fn info_handler<T: ProbeTarget>(State(target): State<Arc<T>>) -> Json<ServerInfo> {
+    let sites = target.sites();
+
+    Json(ServerInfo {
+        version: SERVER_VERSION,
+        ready: sites.iter().all(|site| site.errors.is_empty()),
+        sites,
+    })
+}
@@ -0,0 +1,167 @@
+use std::io::{BufRead, Write};
+
+use garnish_lang::compiler::{build::build_with_data, lex::lex, parse::parse};
+use garnish_lang::simple::{SimpleGarnishData, SimpleGarnishRuntime};
+use garnish_lang::{GarnishData, GarnishRuntime};
+use log::debug;
+
+use crate::context::WebContext;
+use crate::output::OutputMode;
+use crate::{execute_runtime, try_render_current_value, FileType};
+
+const PROMPT: &str = "> ";
+const CONTINUE_PROMPT: &str = ". ";
+const QUIT_COMMANDS: [&str; 2] = [":quit", ":q"];
+
+/// Reads expressions from stdin, building each one into the existing runtime so definitions from the serve path resolve.
+pub fn run_repl(
+    mut runtime: SimpleGarnishRuntime<SimpleGarnishData>,
+    mut context: WebContext,
+) -> Result<(), String> {
+    let stdin = std::io::stdin();
+    let mut input = String::new();
+
+    print_prompt(PROMPT)?;
+
+    for line in stdin.lock().lines() {
+        let line = line.or_else(|e| Err(e.to_string()))?;
+
+        match line.strip_suffix('\\') {
+            Some(l) => {
+                input.push_str(l);
+                input.push('\n');
+                print_prompt(CONTINUE_PROMPT)?;
+                continue;
+            }
+            None => input.push_str(&line),
+        }
+
+        let expression = std::mem::take(&mut input);
+        let expression = expression.trim();
+
+        if QUIT_COMMANDS.contains(&expression) {
+            return Ok(());
+        }
+
+        if !expression.is_empty() {
+            match evaluate(&mut runtime, &mut context, expression) {
+                Err(e) => println!("Error: {}", e),
+                Ok(()) => print_current_value(runtime.get_data_mut()),
+            }
+        }
+
+        print_prompt(PROMPT)?;
+    }
+
+    Ok(())
+}
+
+fn evaluate(
+    runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
+    context: &mut WebContext,
+    expression: &str,
+) -> Result<(), String> {
+    let tokens = lex(expression)?;
+    let parsed = parse(&tokens)?;
+    if parsed.get_nodes().is_empty() {
+        return Err("No expression found in input".into());
+    }
+
+    let index = runtime.get_data().get_jump_table_len();
+    build_with_data(
+        parsed.get_root(),
+        parsed.get_nodes().clone(),
+        runtime.get_data_mut(),
+    )?;
+    let execution_start = match runtime.get_data().get_jump_point(index) {
+        Some(i) => i,
+        None => Err("No jump point found after building expression")?,
+    };
+
+    debug!("Executing expression from {}", execution_start);
+
+    // expressions entered at the prompt receive unit as their input
+    runtime
+        .get_data_mut()
+        .add_unit()
+        .and_then(|unit| runtime.get_data_mut().push_value_stack(unit))
+        .and_then(|_| {
+            runtime
+                .get_data_mut()
+                .set_instruction_cursor(execution_start)
+        })
+        .or_else(|e| Err(format!("Failed to prepare runtime: {:?}", e)))?;
+
+    execute_runtime(runtime, context).or_else(|e| Err(format!("Failed to execute: {:?}", e)))
+}
+
+fn print_current_value(data: &mut SimpleGarnishData) {
+    println!("{}", data.display_current_value());
+
+    for (label, file_type) in [("html", FileType::HTML), ("css", FileType::CSS)] {
+        // values are usually only one of the two, so the failed rendering isn't reported
+        if let Ok(rendered) = try_render_current_value(data, file_type, OutputMode::Compact) {
+            if !rendered.is_empty() {
+                println!("{}: {}", label, rendered);
+            }
+        }
+    }
+}
+
+fn print_prompt(prompt: &str) -> Result<(), String> {
+    let mut stdout = std::io::stdout();
+    write!(stdout, "{}", prompt)
+        .and_then(|_| stdout.flush())
+        .or_else(|e| Err(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::config::ScriptValue;
+    use crate::create_runtime_from_sources;
+
+    use super::*;
+
+    fn site() -> (SimpleGarnishRuntime<SimpleGarnishData>, WebContext) {
+        let (_, runtime, context) = create_runtime_from_sources(
+            vec![(
+                PathBuf::from("/site/index.garnish"),
+                String::from("@Def \"double\" {\n    $ * 2\n}\n\n5\n"),
+            )],
+            "/site",
+        )
+        .unwrap();
+
+        (runtime, context)
+    }
+
+    #[test]
+    fn expressions_use_site_definitions() {
+        let (mut runtime, mut context) = site();
+
+        evaluate(&mut runtime, &mut context, "double` 5").unwrap();
+        assert_eq!(runtime.get_data().display_current_value(), "10");
+    }
+
+    #[test]
+    fn expressions_use_config() {
+        let (mut runtime, mut context) = site();
+        context.insert_value("count", ScriptValue::Integer(4));
+
+        evaluate(&mut runtime, &mut context, "config.count + 1").unwrap();
+        assert_eq!(runtime.get_data().display_current_value(), "5");
+    }
+
+    #[test]
+    fn invalid_expressions_leave_the_runtime_usable() {
+        let (mut runtime, mut context) = site();
+
+        assert!(evaluate(&mut runtime, &mut context, "(((").is_err());
+        assert!(evaluate(&mut runtime, &mut context, "   ").is_err());
+
+        evaluate(&mut runtime, &mut context, "1 + 2").unwrap();
+        assert_eq!(runtime.get_data().display_current_value(), "3");
+    }
+}
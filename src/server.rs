@@ -0,0 +1,532 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::extract::connect_info::IntoMakeServiceWithConnectInfo;
+use axum::Router;
+use log::{error, info};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_util::sync::{CancellationToken, PollSemaphore, WaitForCancellationFutureOwned};
+use tower::Service;
+
+use crate::access::with_access_log;
+use crate::compression::with_compression;
+use crate::config::{ServerConfig, DRAIN_TIMEOUT_SECS_DEFAULT};
+use crate::metrics::{serve_metrics, with_metrics};
+use crate::tls::serve_tls;
+
+/// Smallest read buffer hyper accepts for HTTP/1 connections.
+const HTTP1_MIN_BUF_SIZE: usize = 8192;
+
+/// Serves the app on the configured address until the server stops, over HTTPS when TLS is configured.
+pub async fn serve(app: Router, settings: &ServerConfig) -> Result<(), String> {
+    serve_until(app, settings, shutdown_signal()).await
+}
+
+/// Same as [`serve`] but stops when the future resolves, with the reason to log, instead of on a signal.
+pub async fn serve_until<F: Future<Output = &'static str>>(
+    app: Router,
+    settings: &ServerConfig,
+    stop: F,
+) -> Result<(), String> {
+    let address: SocketAddr = settings
+        .bind
+        .parse()
+        .or_else(|e| Err(format!("Invalid bind address {:?}. Reason: {}", settings.bind, e)))?;
+
+    let app = with_metrics(app, &settings.metrics);
+
+    let mut listeners = Listeners::new();
+
+    if let (true, Some(bind)) = (settings.metrics.enabled, &settings.metrics.bind) {
+        let bind = bind.clone();
+        let path = settings.metrics.path.clone();
+
+        listeners.spawn("Metrics listener", move |stopped| async move {
+            serve_metrics(&bind, &path, stopped).await
+        });
+    }
+
+    // inside compression, so logged sizes are of the uncompressed body
+    let app = with_access_log(app, &settings.logging.access)?;
+
+    let app = with_compression(app, &settings.compression);
+
+    let make_service = ConnectionLimit::new(
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+        settings.limits.max_connections,
+    );
+
+    if let Some(tls) = &settings.tls {
+        return serve_tls(make_service, address, tls, settings, listeners, stop).await;
+    }
+
+    let mut server = axum::Server::try_bind(&address)
+        .or_else(|e| Err(format!("Failed to bind {}. Reason: {}", address, e)))?
+        .http1_only(!settings.http.http2)
+        .http1_keepalive(settings.http.keep_alive)
+        .http2_keep_alive_interval(keep_alive_interval(settings))
+        .http2_keep_alive_timeout(Duration::from_secs(settings.http.keep_alive_interval_secs));
+
+    if let Some(max) = settings.limits.max_header_bytes {
+        server = server
+            .http1_max_buf_size(max_buf_size(max))
+            .http2_max_header_list_size(max);
+    }
+
+    if let Some(secs) = settings.limits.read_timeout_secs {
+        server = server.http1_header_read_timeout(Duration::from_secs(secs));
+    }
+
+    let server = server
+        .serve(make_service)
+        .with_graceful_shutdown(listeners.stopped());
+
+    info!("Listening on {}", address);
+    run_until_stopped(server, listeners, settings, stop).await
+}
+
+/// Runs the server until it fails or the stop future resolves. Then every listener stops accepting connections,
+/// and requests in flight on any of them get the drain timeout to finish.
+pub(crate) async fn run_until_stopped<S, E, F>(
+    server: S,
+    listeners: Listeners,
+    settings: &ServerConfig,
+    stop: F,
+) -> Result<(), String>
+where
+    S: Future<Output = Result<(), E>>,
+    E: Display,
+    F: Future<Output = &'static str>,
+{
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => {
+            listeners.stop();
+            return result.or_else(|e| Err(format!("Server error. Reason: {}", e)));
+        }
+        reason = stop => {
+            info!("Received {}. No longer accepting connections.", reason);
+            listeners.stop();
+        }
+    }
+
+    let drain = drain_timeout(settings);
+    let drained = async {
+        let result = server.await;
+        listeners.join().await;
+        result
+    };
+
+    let result = match tokio::time::timeout(drain, drained).await {
+        Err(_) => Err(format!(
+            "Requests were still in flight after {} seconds. Closing their connections.",
+            drain.as_secs()
+        )),
+        Ok(result) => result.or_else(|e| Err(format!("Server error. Reason: {}", e))),
+    };
+
+    stopped_log(&result);
+    result
+}
+
+/// Listeners running next to the server, like the metrics and redirect listeners, which stop with it.
+pub(crate) struct Listeners {
+    stop: CancellationToken,
+    running: Vec<JoinHandle<()>>,
+}
+
+impl Listeners {
+    pub(crate) fn new() -> Self {
+        Self {
+            stop: CancellationToken::new(),
+            running: vec![],
+        }
+    }
+
+    /// Runs a listener until it fails or is stopped. It's given a future that resolves when it should stop
+    /// accepting connections, after which it should finish once its requests in flight have finished.
+    pub(crate) fn spawn<L, F>(&mut self, name: &'static str, listener: L)
+    where
+        L: FnOnce(WaitForCancellationFutureOwned) -> F,
+        F: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let listener = listener(self.stopped());
+
+        self.running.push(tokio::spawn(async move {
+            if let Err(e) = listener.await {
+                error!("{} stopped. Reason: {}", name, e);
+            }
+        }));
+    }
+
+    /// Resolves once the listeners are stopped.
+    pub(crate) fn stopped(&self) -> WaitForCancellationFutureOwned {
+        self.stop.clone().cancelled_owned()
+    }
+
+    fn stop(&self) {
+        self.stop.cancel();
+    }
+
+    async fn join(self) {
+        for listener in self.running {
+            let _ = listener.await;
+        }
+    }
+}
+
+/// Resolves when the process is asked to stop, with the name of the signal.
+pub async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Err(e) => error!("Failed to listen for SIGTERM. Reason: {}", e),
+            Ok(mut terminate) => {
+                return tokio::select! {
+                    _ = tokio::signal::ctrl_c() => "SIGINT",
+                    _ = terminate.recv() => "SIGTERM",
+                };
+            }
+        }
+    }
+
+    match tokio::signal::ctrl_c().await {
+        Err(e) => {
+            error!("Failed to listen for Ctrl-C. Reason: {}", e);
+            // without signals the server can only be killed, so never resolve
+            std::future::pending().await
+        }
+        Ok(()) => "SIGINT",
+    }
+}
+
+pub(crate) fn drain_timeout(settings: &ServerConfig) -> Duration {
+    Duration::from_secs(
+        settings
+            .limits
+            .drain_timeout_secs
+            .unwrap_or(DRAIN_TIMEOUT_SECS_DEFAULT),
+    )
+}
+
+/// Logs how the server stopped and flushes the log, since the process exits right after.
+pub(crate) fn stopped_log(result: &Result<(), String>) {
+    match result {
+        Ok(()) => info!("All requests finished. Stopped."),
+        Err(e) => error!("{}", e),
+    }
+
+    log::logger().flush();
+}
+
+pub(crate) fn keep_alive_interval(settings: &ServerConfig) -> Option<Duration> {
+    match settings.http.keep_alive_interval_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+pub(crate) fn max_buf_size(max_header_bytes: u32) -> usize {
+    (max_header_bytes as usize).max(HTTP1_MIN_BUF_SIZE)
+}
+
+pub(crate) type ServerMakeService =
+    ConnectionLimit<IntoMakeServiceWithConnectInfo<Router, SocketAddr>>;
+
+/// Wraps a make service so at most a number of connections are open at once.
+/// Each connection's service holds a permit until the connection closes. While none are left,
+/// the make service isn't ready and the server stops accepting connections.
+pub struct ConnectionLimit<M> {
+    inner: M,
+    semaphore: Option<PollSemaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<M> ConnectionLimit<M> {
+    /// No limit is applied when max is None.
+    pub fn new(inner: M, max: Option<usize>) -> Self {
+        Self {
+            inner,
+            semaphore: max.map(|max| PollSemaphore::new(Arc::new(Semaphore::new(max)))),
+            permit: None,
+        }
+    }
+}
+
+impl<M, T> Service<T> for ConnectionLimit<M>
+where
+    M: Service<T>,
+    M::Future: Send + 'static,
+{
+    type Response = Limited<M::Response>;
+    type Error = M::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let (Some(semaphore), None) = (&mut self.semaphore, &self.permit) {
+            match semaphore.poll_acquire(cx) {
+                Poll::Pending => return Poll::Pending,
+                // the semaphore is never closed
+                Poll::Ready(permit) => self.permit = permit,
+            }
+        }
+
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        let permit = self.permit.take();
+        let future = self.inner.call(target);
+
+        Box::pin(async move {
+            future.await.map(|inner| Limited {
+                inner,
+                _permit: permit,
+            })
+        })
+    }
+}
+
+/// Service of one connection, releasing its connection permit when dropped.
+pub struct Limited<S> {
+    inner: S,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<S, R> Service<R> for Limited<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        self.inner.call(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::{oneshot, Notify};
+
+    use crate::config::{LimitsConfig, MetricsConfig};
+
+    fn free_address() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn settings(drain_timeout_secs: u64, metrics_bind: Option<String>) -> ServerConfig {
+        ServerConfig {
+            bind: free_address(),
+            limits: LimitsConfig {
+                drain_timeout_secs: Some(drain_timeout_secs),
+                ..LimitsConfig::default()
+            },
+            metrics: MetricsConfig {
+                enabled: metrics_bind.is_some(),
+                bind: metrics_bind,
+                ..MetricsConfig::default()
+            },
+            ..ServerConfig::default()
+        }
+    }
+
+    /// App whose only route notifies when a request arrives, then takes the given time to respond.
+    fn slow_app(started: Arc<Notify>, delay: Duration) -> Router {
+        Router::new().route(
+            "/slow",
+            get(move || async move {
+                started.notify_one();
+                tokio::time::sleep(delay).await;
+                "done"
+            }),
+        )
+    }
+
+    async fn connect(address: &str) -> TcpStream {
+        // the server is started in another task, so it may not be listening yet
+        loop {
+            match TcpStream::connect(address).await {
+                Ok(stream) => return stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    async fn get_slow(address: String) -> String {
+        let mut stream = connect(&address).await;
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    /// Starts the server, sends a request and stops the server once the request arrived.
+    /// Returns the server's result and the response.
+    async fn stop_during_request(
+        settings: ServerConfig,
+        delay: Duration,
+    ) -> (Result<(), String>, String) {
+        let started = Arc::new(Notify::new());
+        let (stop, stopped) = oneshot::channel::<()>();
+        let address = settings.bind.clone();
+        let app = slow_app(started.clone(), delay);
+
+        let server = tokio::spawn(async move {
+            serve_until(app, &settings, async {
+                let _ = stopped.await;
+                "stop"
+            })
+            .await
+        });
+
+        let response = tokio::spawn(get_slow(address));
+        started.notified().await;
+        stop.send(()).unwrap();
+
+        (server.await.unwrap(), response.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn requests_in_flight_finish_after_stopping() {
+        let (result, response) =
+            stop_during_request(settings(10, None), Duration::from_millis(200)).await;
+
+        assert_eq!(result, Ok(()));
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("done"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn stopping_fails_when_requests_outlast_the_drain_timeout() {
+        let (result, _) = stop_during_request(settings(0, None), Duration::from_secs(2)).await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn metrics_listener_stops_with_the_server() {
+        let metrics_address = free_address();
+        let settings = settings(10, Some(metrics_address.clone()));
+        let (stop, stopped) = oneshot::channel::<()>();
+
+        let server = tokio::spawn(async move {
+            serve_until(Router::new(), &settings, async {
+                let _ = stopped.await;
+                "stop"
+            })
+            .await
+        });
+
+        connect(&metrics_address).await;
+        stop.send(()).unwrap();
+
+        assert_eq!(server.await.unwrap(), Ok(()));
+        assert!(TcpStream::connect(&metrics_address).await.is_err());
+    }
+
+    /// Starts the server with an app that responds with "ok", returning the sender that stops it.
+    fn start_ok_app(settings: ServerConfig) -> oneshot::Sender<()> {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+
+        tokio::spawn(async move {
+            serve_until(app, &settings, async {
+                let _ = stopped.await;
+                "stop"
+            })
+            .await
+        });
+
+        stop
+    }
+
+    /// Sends a request on a kept alive connection and reads until the response body arrived.
+    async fn get_ok(stream: &mut TcpStream) -> String {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = vec![];
+        let mut buf = [0; 1024];
+        while !response.ends_with(b"ok") {
+            match stream.read(&mut buf).await.unwrap() {
+                0 => break,
+                n => response.extend_from_slice(&buf[..n]),
+            }
+        }
+
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_wait_for_an_open_one_to_close() {
+        let mut settings = settings(10, None);
+        settings.limits.max_connections = Some(1);
+        let address = settings.bind.clone();
+        let _stop = start_ok_app(settings);
+
+        let mut first = connect(&address).await;
+        assert!(get_ok(&mut first).await.starts_with("HTTP/1.1 200 OK"));
+
+        // the operating system completes the connection, but the server doesn't serve it yet
+        let mut second = connect(&address).await;
+        let waiting = tokio::time::timeout(Duration::from_millis(300), get_ok(&mut second)).await;
+        assert!(waiting.is_err());
+
+        drop(first);
+        let mut buf = [0; 1024];
+        let n = tokio::time::timeout(Duration::from_secs(5), second.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200 OK"));
+    }
+
+    #[tokio::test]
+    async fn headers_over_the_limit_are_rejected() {
+        let mut settings = settings(10, None);
+        settings.limits.max_header_bytes = Some(HTTP1_MIN_BUF_SIZE as u32);
+        let address = settings.bind.clone();
+        let _stop = start_ok_app(settings);
+
+        let mut stream = connect(&address).await;
+        assert!(get_ok(&mut stream).await.starts_with("HTTP/1.1 200 OK"));
+
+        let large = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-Large: {}\r\n\r\n",
+            "a".repeat(HTTP1_MIN_BUF_SIZE * 2)
+        );
+        let mut stream = connect(&address).await;
+        stream.write_all(large.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+    }
+}
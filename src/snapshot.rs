@@ -0,0 +1,360 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::body::{Body, HttpBody};
+use axum::extract::State;
+use axum::http::{HeaderMap, Request};
+use log::{debug, info};
+use serde::Deserialize;
+use similar::{ChangeTag, TextDiff};
+
+use crate::{serve_request, SharedState};
+
+pub const FIXTURE_EXTENSION: &str = "fixtures.json";
+pub const SNAPSHOT_EXTENSION: &str = "snap";
+
+/// Request to render for a route, read from a `<file>.fixtures.json` sidecar next to the garnish file.
+#[derive(Clone, Debug, Deserialize)]
+struct Fixture {
+    name: String,
+    #[serde(default = "default_method")]
+    method: String,
+    path: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    body: String,
+}
+
+fn default_method() -> String {
+    String::from("GET")
+}
+
+#[derive(Clone, Debug)]
+struct SnapshotRequest {
+    name: String,
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+enum SnapshotResult {
+    Matched,
+    Written,
+    Missing(String),
+    Mismatched(String, String),
+}
+
+/// Renders every route through [`serve_request`] and compares the output to the stored snapshots.
+pub async fn run_snapshots(
+    state: Arc<SharedState>,
+    snapshot_path: &Path,
+    update: bool,
+) -> Result<(), String> {
+    let requests = create_requests(&state)?;
+
+    if update {
+        fs::create_dir_all(snapshot_path).or_else(|e| Err(e.to_string()))?;
+    }
+
+    let mut failed = 0;
+    let mut expected_files = HashSet::new();
+
+    for request in requests.iter() {
+        let actual = render(state.clone(), request).await?;
+
+        let mut path = snapshot_path.to_path_buf();
+        path.push(format!("{}.{}", request.name, SNAPSHOT_EXTENSION));
+        expected_files.insert(path.clone());
+
+        let result = match fs::read_to_string(&path) {
+            Ok(expected) if expected == actual => SnapshotResult::Matched,
+            _ if update => {
+                fs::write(&path, &actual).or_else(|e| Err(e.to_string()))?;
+                SnapshotResult::Written
+            }
+            Ok(expected) => SnapshotResult::Mismatched(expected, actual),
+            Err(_) => SnapshotResult::Missing(actual),
+        };
+
+        match result {
+            SnapshotResult::Matched => println!("ok       {}", request.name),
+            SnapshotResult::Written => println!("updated  {}", request.name),
+            SnapshotResult::Missing(actual) => {
+                failed += 1;
+                println!("missing  {} ({})", request.name, path.to_string_lossy());
+                print_diff("", &actual);
+            }
+            SnapshotResult::Mismatched(expected, actual) => {
+                failed += 1;
+                println!("FAILED   {}", request.name);
+                print_diff(&expected, &actual);
+            }
+        }
+    }
+
+    for path in stale_snapshots(snapshot_path, &expected_files)? {
+        match update {
+            true => {
+                fs::remove_file(&path).or_else(|e| Err(e.to_string()))?;
+                println!("removed  {}", path.to_string_lossy());
+            }
+            false => {
+                failed += 1;
+                println!("stale    {} (no matching route, removed by --update)", path.to_string_lossy());
+            }
+        }
+    }
+
+    println!();
+    println!("{} snapshots, {} failed", requests.len(), failed);
+
+    match failed {
+        0 => Ok(()),
+        n => Err(format!(
+            "{} snapshots did not match. Run with --update to accept changes.",
+            n
+        )),
+    }
+}
+
+async fn render(state: Arc<SharedState>, request: &SnapshotRequest) -> Result<String, String> {
+    debug!(
+        "Rendering snapshot {} with {} {}",
+        request.name, request.method, request.path
+    );
+
+    let mut builder = Request::builder()
+        .method(request.method.as_str())
+        .uri(request.path.as_str());
+
+    for (name, value) in request.headers.iter() {
+        builder = builder.header(name, value);
+    }
+
+    let request = builder
+        .body(Body::from(request.body.clone()))
+        .or_else(|e| Err(e.to_string()))?;
+
+    // same entry point as live requests, so rewrites, static files and configured headers apply
+    let response = serve_request(State(state.clone()), request).await;
+
+    let mut headers = vec![format!(
+        "content-type: {}",
+        header_text(response.headers(), "Content-Type")
+    )];
+
+    for name in state.settings().headers.keys() {
+        if response.headers().contains_key(name.as_str()) {
+            headers.push(format!(
+                "{}: {}",
+                name.to_lowercase(),
+                header_text(response.headers(), name)
+            ));
+        }
+    }
+
+    let status = response.status().as_u16();
+    let mut body = response.into_body();
+    let mut bytes = vec![];
+
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.or_else(|e| Err(e.to_string()))?);
+    }
+
+    Ok(format!(
+        "status: {}\n{}\n\n{}\n",
+        status,
+        headers.join("\n"),
+        String::from_utf8_lossy(&bytes)
+    ))
+}
+
+fn header_text(headers: &HeaderMap, name: &str) -> String {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Snapshot files in the snapshot path that no request wrote or compared against.
+fn stale_snapshots(
+    snapshot_path: &Path,
+    expected: &HashSet<PathBuf>,
+) -> Result<Vec<PathBuf>, String> {
+    if !snapshot_path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut stale = vec![];
+
+    for entry in fs::read_dir(snapshot_path).or_else(|e| Err(e.to_string()))? {
+        let path = entry.or_else(|e| Err(e.to_string()))?.path();
+        let is_snapshot = path
+            .extension()
+            .map(|e| e == SNAPSHOT_EXTENSION)
+            .unwrap_or(false);
+
+        if is_snapshot && !expected.contains(&path) {
+            stale.push(path);
+        }
+    }
+
+    stale.sort();
+    Ok(stale)
+}
+
+fn create_requests(state: &SharedState) -> Result<Vec<SnapshotRequest>, String> {
+    let mut routes = state.route_mapping.values().collect::<Vec<_>>();
+    routes.sort_by(|a, b| a.route.cmp(&b.route));
+
+    let mut requests = vec![];
+    let mut seen = HashSet::new();
+    let mut fixture_files = HashSet::new();
+
+    for info in routes {
+        // method routes are stored as "<METHOD>@<route>"
+        let (method, route) = match info.route.split_once('@') {
+            Some((method, route)) => (method.to_string(), route.to_string()),
+            None => (String::from("GET"), info.route.clone()),
+        };
+
+        // root script and GET route can resolve to the same request
+        if seen.insert((method.clone(), route.clone())) {
+            requests.push(SnapshotRequest {
+                name: snapshot_name(&info.route),
+                method,
+                path: format!("/{}", route),
+                headers: HashMap::new(),
+                body: String::new(),
+            });
+        }
+
+        if !fixture_files.insert(info.path.clone()) {
+            continue;
+        }
+
+        for fixture in read_fixtures(&info.path)? {
+            requests.push(SnapshotRequest {
+                name: format!("{}-{}", snapshot_name(&route), snapshot_name(&fixture.name)),
+                method: fixture.method,
+                path: fixture.path.unwrap_or(format!("/{}", route)),
+                headers: fixture.headers,
+                body: fixture.body,
+            });
+        }
+    }
+
+    Ok(requests)
+}
+
+fn read_fixtures(garnish_path: &PathBuf) -> Result<Vec<Fixture>, String> {
+    let path = garnish_path.with_extension(FIXTURE_EXTENSION);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    info!("Reading fixtures from {}", path.to_string_lossy());
+
+    let text = fs::read_to_string(&path).or_else(|e| Err(e.to_string()))?;
+    serde_json::from_str(&text).or_else(|e| {
+        Err(format!(
+            "Failed to read fixtures from {}. Reason: {}",
+            path.to_string_lossy(),
+            e
+        ))
+    })
+}
+
+/// File name for a route or fixture name. Slashes become underscores and everything else
+/// that isn't alphanumeric, '.' or '@' is percent encoded, so different routes never share a file
+/// and '-' is left to separate fixture names.
+fn snapshot_name(route: &str) -> String {
+    let mut name = String::new();
+
+    for c in route.chars() {
+        match c {
+            '/' => name.push('_'),
+            c if c.is_ascii_alphanumeric() || c == '.' || c == '@' => name.push(c),
+            c => {
+                let mut buffer = [0; 4];
+                for byte in c.encode_utf8(&mut buffer).bytes() {
+                    name.push_str(&format!("%{:02X}", byte));
+                }
+            }
+        }
+    }
+
+    name
+}
+
+fn print_diff(expected: &str, actual: &str) {
+    let expected = split_markup(expected);
+    let actual = split_markup(actual);
+
+    let diff = TextDiff::from_lines(&expected, &actual);
+    for change in diff.iter_all_changes() {
+        let sign = match change.tag() {
+            ChangeTag::Delete => "-",
+            ChangeTag::Insert => "+",
+            ChangeTag::Equal => " ",
+        };
+        print!("    {} {}", sign, change);
+    }
+    println!();
+}
+
+// rendered html and css are written on a single line
+// break after tags and rules so the diff points at the changed part
+fn split_markup(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        result.push(c);
+        let next = chars.peek();
+        let split = match c {
+            '>' => next == Some(&'<'),
+            '{' | ';' | '}' => next.is_some() && next != Some(&'\n'),
+            _ => false,
+        };
+
+        if split {
+            result.push('\n');
+        }
+    }
+
+    if !result.ends_with('\n') {
+        result.push('\n');
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_name_keeps_readable_routes() {
+        assert_eq!(snapshot_name("index"), "index");
+        assert_eq!(snapshot_name("GET@blog/index"), "GET@blog_index");
+    }
+
+    #[test]
+    fn snapshot_name_is_unique_per_route() {
+        let routes = ["a/b", "a_b", "a%5Fb", "a-b", "a b", "a/b-c", "a/b_c", "é"];
+        let names = routes.iter().map(|r| snapshot_name(r)).collect::<HashSet<String>>();
+
+        assert_eq!(names.len(), routes.len());
+    }
+
+    #[test]
+    fn snapshot_name_leaves_dash_for_fixtures() {
+        assert!(!snapshot_name("a-b").contains('-'));
+    }
+}
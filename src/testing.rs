@@ -0,0 +1,211 @@
+use std::path::PathBuf;
+
+use garnish_lang::simple::{SimpleGarnishData, SimpleGarnishRuntime};
+use garnish_lang::{GarnishData, GarnishDataType, GarnishRuntime};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::context::{SharedContext, WebContext};
+use crate::execute_runtime;
+
+/// Test expression registered with a @Test annotation.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TestInfo {
+    path: PathBuf,
+    name: String,
+    table_index: usize,
+}
+
+impl TestInfo {
+    pub fn new<T: Into<String>>(path: PathBuf, name: T, table_index: usize) -> Self {
+        Self {
+            path,
+            name: name.into(),
+            table_index,
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+enum TestResult {
+    Passed,
+    Failed(String),
+}
+
+/// Runs every registered test against its own copy of the runtime and prints results grouped by file.
+pub fn run_tests(
+    runtime: &SimpleGarnishRuntime<SimpleGarnishData>,
+    context: &WebContext,
+) -> Result<(), String> {
+    // tests are registered file by file, so grouping consecutive entries keeps file order
+    let mut files: Vec<(&PathBuf, Vec<(&TestInfo, TestResult)>)> = vec![];
+
+    for test in context.tests() {
+        let result = run_test(test, runtime.clone(), SharedContext::new(context));
+
+        match files.last_mut() {
+            Some((path, results)) if *path == test.path() => results.push((test, result)),
+            _ => files.push((test.path(), vec![(test, result)])),
+        }
+    }
+
+    let mut total_passed = 0;
+    let mut total_failed = 0;
+
+    for (path, results) in files {
+        println!("{}", path.to_string_lossy());
+
+        for (test, result) in results.iter() {
+            match result {
+                TestResult::Passed => println!("    ok      {}", test.name()),
+                TestResult::Failed(reason) => {
+                    println!("    FAILED  {} - {}", test.name(), reason)
+                }
+            }
+        }
+
+        let passed = results
+            .iter()
+            .filter(|(_, r)| *r == TestResult::Passed)
+            .count();
+        let failed = results.len() - passed;
+
+        println!("    {} passed, {} failed", passed, failed);
+        println!();
+
+        total_passed += passed;
+        total_failed += failed;
+    }
+
+    println!("Total: {} passed, {} failed", total_passed, total_failed);
+
+    match total_failed {
+        0 => Ok(()),
+        n => Err(format!(
+            "{} of {} tests failed",
+            n,
+            total_passed + total_failed
+        )),
+    }
+}
+
+fn run_test(
+    test: &TestInfo,
+    mut runtime: SimpleGarnishRuntime<SimpleGarnishData>,
+    mut context: SharedContext,
+) -> TestResult {
+    debug!("Running test {} in {:?}", test.name(), test.path());
+
+    let start = match runtime.get_data().get_jump_point(test.table_index) {
+        None => {
+            return TestResult::Failed(format!(
+                "No jump point found for test at index {}",
+                test.table_index
+            ))
+        }
+        Some(s) => s,
+    };
+
+    // tests receive unit as their input
+    if let Err(e) = runtime
+        .get_data_mut()
+        .add_unit()
+        .and_then(|unit| runtime.get_data_mut().push_value_stack(unit))
+        .and_then(|_| runtime.get_data_mut().set_instruction_cursor(start))
+    {
+        return TestResult::Failed(format!("Failed to prepare runtime: {:?}", e));
+    }
+
+    if let Err(e) = execute_runtime(&mut runtime, &mut context) {
+        return TestResult::Failed(format!("Runtime error: {:?}", e));
+    }
+
+    let data = runtime.get_data();
+    match data.get_current_value().map(|v| data.get_data_type(v)) {
+        None => TestResult::Failed("No value after execution".into()),
+        Some(Err(e)) => TestResult::Failed(format!("Failed to read result: {:?}", e)),
+        Some(Ok(GarnishDataType::False)) | Some(Ok(GarnishDataType::Unit)) => {
+            TestResult::Failed(format!("Result was {}", data.display_current_value()))
+        }
+        Some(Ok(_)) => TestResult::Passed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use garnish_lang::compiler::{build::build_with_data, lex::lex, parse::parse};
+
+    use super::*;
+
+    fn add_test(
+        runtime: &mut SimpleGarnishRuntime<SimpleGarnishData>,
+        context: &mut WebContext,
+        name: &str,
+        expression: &str,
+    ) -> TestInfo {
+        let tokens = lex(expression).unwrap();
+        let parsed = parse(&tokens).unwrap();
+        let index = runtime.get_data().get_jump_table_len();
+        build_with_data(
+            parsed.get_root(),
+            parsed.get_nodes().clone(),
+            runtime.get_data_mut(),
+        )
+        .unwrap();
+
+        let test = TestInfo::new(PathBuf::from("/site/index.garnish"), name, index);
+        context.insert_test(test.clone());
+        test
+    }
+
+    #[test]
+    fn truthy_results_pass_and_false_or_unit_fail() {
+        let mut runtime = SimpleGarnishRuntime::new(SimpleGarnishData::new());
+        let mut context = WebContext::new();
+
+        let passing = add_test(&mut runtime, &mut context, "adds", "1 + 1 == 2");
+        let failing = add_test(&mut runtime, &mut context, "compares", "1 == 2");
+        let empty = add_test(&mut runtime, &mut context, "empty", "()");
+
+        let run = |test: &TestInfo| run_test(test, runtime.clone(), SharedContext::new(&context));
+
+        assert_eq!(run(&passing), TestResult::Passed);
+        assert_eq!(run(&failing), TestResult::Failed("Result was False".into()));
+        assert_eq!(run(&empty), TestResult::Failed("Result was ()".into()));
+    }
+
+    #[test]
+    fn missing_jump_points_fail_the_test() {
+        let runtime = SimpleGarnishRuntime::new(SimpleGarnishData::new());
+        let context = WebContext::new();
+        let test = TestInfo::new(PathBuf::from("/site/index.garnish"), "missing", 10);
+
+        assert_eq!(
+            run_test(&test, runtime, SharedContext::new(&context)),
+            TestResult::Failed("No jump point found for test at index 10".into())
+        );
+    }
+
+    #[test]
+    fn any_failure_fails_the_run() {
+        let mut runtime = SimpleGarnishRuntime::new(SimpleGarnishData::new());
+        let mut context = WebContext::new();
+
+        add_test(&mut runtime, &mut context, "passes", "1 == 1");
+        assert_eq!(run_tests(&runtime, &context), Ok(()));
+
+        add_test(&mut runtime, &mut context, "fails", "1 == 2");
+        assert_eq!(
+            run_tests(&runtime, &context),
+            Err("1 of 2 tests failed".into())
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::header::HOST;
+use axum::http::Request;
+use axum::response::Redirect;
+use axum::routing::any;
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::{Handle, HttpConfig};
+use log::{debug, error, info};
+use rustls::{Certificate, PrivateKey};
+use rustls_pemfile::Item;
+
+use crate::config::{ServerConfig, TlsConfig};
+use crate::server::{
+    keep_alive_interval, max_buf_size, run_until_stopped, Listeners, ServerMakeService,
+};
+use crate::vhost::strip_port;
+
+/// Loads the certificate and key files.
+/// Clients can only negotiate HTTP/2 when it's enabled, since it's offered through ALPN.
+pub async fn load_tls(tls: &TlsConfig, http2: bool) -> Result<RustlsConfig, String> {
+    let config = rustls_config(tls, http2).await?;
+
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+async fn rustls_config(tls: &TlsConfig, http2: bool) -> Result<rustls::ServerConfig, String> {
+    let cert = tokio::fs::read(&tls.cert).await.or_else(|e| {
+        Err(format!(
+            "Failed to read certificate {:?}. Reason: {}",
+            tls.cert, e
+        ))
+    })?;
+    let key = tokio::fs::read(&tls.key)
+        .await
+        .or_else(|e| Err(format!("Failed to read key {:?}. Reason: {}", tls.key, e)))?;
+
+    let certs = rustls_pemfile::certs(&mut cert.as_slice())
+        .or_else(|e| Err(format!("Invalid certificate {:?}. Reason: {}", tls.cert, e)))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let items = rustls_pemfile::read_all(&mut key.as_slice())
+        .or_else(|e| Err(format!("Invalid key {:?}. Reason: {}", tls.key, e)))?;
+
+    let key = match items.into_iter().find_map(|item| match item {
+        Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(key),
+        _ => None,
+    }) {
+        None => Err(format!("No supported private key found in {:?}", tls.key))?,
+        Some(key) => PrivateKey(key),
+    };
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .or_else(|e| {
+            Err(format!(
+                "Failed to use certificate {:?} and key {:?}. Reason: {}",
+                tls.cert, tls.key, e
+            ))
+        })?;
+
+    config.alpn_protocols = match http2 {
+        true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        false => vec![b"http/1.1".to_vec()],
+    };
+
+    Ok(config)
+}
+
+/// Serves over HTTPS until the server stops.
+/// Also starts the redirect listener and reloading of changed certificate files, when configured.
+pub(crate) async fn serve_tls<F: Future<Output = &'static str>>(
+    make_service: ServerMakeService,
+    address: SocketAddr,
+    tls: &TlsConfig,
+    settings: &ServerConfig,
+    mut listeners: Listeners,
+    stop: F,
+) -> Result<(), String> {
+    let rustls = load_tls(tls, settings.http.http2).await?;
+
+    if let Some(bind) = &tls.redirect_bind {
+        let bind = bind.clone();
+        let port = address.port();
+
+        listeners.spawn("Redirect listener", move |stopped| async move {
+            redirect_http(&bind, port, stopped).await
+        });
+    }
+
+    tokio::spawn(reload_certificates(
+        rustls.clone(),
+        tls.clone(),
+        settings.http.http2,
+        Duration::from_secs(tls.reload_interval_secs),
+    ));
+
+    let mut http_config = HttpConfig::new();
+    http_config
+        .http1_only(!settings.http.http2)
+        .http1_keep_alive(settings.http.keep_alive)
+        .http2_keep_alive_interval(keep_alive_interval(settings))
+        .http2_keep_alive_timeout(Duration::from_secs(settings.http.keep_alive_interval_secs));
+
+    if let Some(max) = settings.limits.max_header_bytes {
+        http_config
+            .max_buf_size(max_buf_size(max))
+            .http2_max_header_list_size(max);
+    }
+
+    if let Some(secs) = settings.limits.read_timeout_secs {
+        http_config.http1_header_read_timeout(Duration::from_secs(secs));
+    }
+
+    let handle = Handle::new();
+    let server = axum_server::bind_rustls(address, rustls)
+        .http_config(http_config.build())
+        .handle(handle.clone())
+        .serve(make_service);
+
+    let stopped = listeners.stopped();
+    tokio::spawn(async move {
+        stopped.await;
+        handle.graceful_shutdown(None);
+    });
+
+    info!("Listening on https://{}", address);
+    run_until_stopped(server, listeners, settings, stop).await
+}
+
+/// Listens for plain HTTP requests and redirects them to the same host and path over HTTPS.
+/// Stops accepting connections once the stopped future resolves.
+pub async fn redirect_http<F: Future<Output = ()>>(
+    bind: &str,
+    https_port: u16,
+    stopped: F,
+) -> Result<(), String> {
+    let address: SocketAddr = bind
+        .parse()
+        .or_else(|e| Err(format!("Invalid redirect bind address {:?}. Reason: {}", bind, e)))?;
+
+    let app = Router::new()
+        .route("/", any(redirect_handler))
+        .route("/*path", any(redirect_handler))
+        .with_state(https_port);
+
+    info!("Redirecting http://{} to HTTPS", address);
+    axum::Server::bind(&address)
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(stopped)
+        .await
+        .or_else(|e| Err(format!("Server error. Reason: {}", e)))
+}
+
+async fn redirect_handler(State(https_port): State<u16>, request: Request<Body>) -> Redirect {
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(strip_port)
+        .unwrap_or("localhost");
+
+    let port = match https_port {
+        443 => String::new(),
+        port => format!(":{}", port),
+    };
+
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    // bracketed IPv6 hosts lose their brackets when the port is stripped
+    let host = match host.contains(':') {
+        true => format!("[{}]", host),
+        false => host.to_string(),
+    };
+
+    debug!("Redirecting {} to HTTPS", path);
+    Redirect::permanent(&format!("https://{}{}{}", host, port, path))
+}
+
+/// Checks the certificate files at each interval, loading them again when either changes.
+/// New connections use the new certificate, existing connections keep the one they started with.
+pub async fn reload_certificates(
+    rustls: RustlsConfig,
+    tls: TlsConfig,
+    http2: bool,
+    interval: Duration,
+) {
+    let mut modified = modified_times(&tls);
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let current = modified_times(&tls);
+        if current == modified {
+            continue;
+        }
+
+        modified = current;
+
+        match rustls_config(&tls, http2).await {
+            Err(e) => error!("Failed to reload certificate. Keeping the current one. Reason: {}", e),
+            Ok(config) => {
+                rustls.reload_from_config(Arc::new(config));
+                info!("Reloaded certificate {:?}", tls.cert);
+            }
+        }
+    }
+}
+
+fn modified_times(tls: &TlsConfig) -> Vec<Option<SystemTime>> {
+    [&tls.cert, &tls.key]
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::LOCATION;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    use super::*;
+
+    fn localhost() -> TlsConfig {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+
+        TlsConfig::new(dir.join("localhost.pem"), dir.join("localhost-key.pem"))
+    }
+
+    #[tokio::test]
+    async fn alpn_offers_http2_only_when_enabled() {
+        let config = rustls_config(&localhost(), true).await.unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+
+        let config = rustls_config(&localhost(), false).await.unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"http/1.1".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn key_file_without_a_key_is_an_error() {
+        let mut tls = localhost();
+        tls.key = tls.cert.clone();
+
+        assert!(rustls_config(&tls, true).await.is_err());
+    }
+
+    async fn redirect_location(https_port: u16, host: &str, uri: &str) -> String {
+        let request = Request::builder()
+            .uri(uri)
+            .header(HOST, host)
+            .body(Body::empty())
+            .unwrap();
+        let response = redirect_handler(State(https_port), request).await.into_response();
+
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        response.headers()[LOCATION].to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn redirects_keep_the_host_path_and_query() {
+        assert_eq!(
+            redirect_location(443, "example.com:80", "/blog/post?page=2").await,
+            "https://example.com/blog/post?page=2"
+        );
+        assert_eq!(
+            redirect_location(8443, "example.com", "/").await,
+            "https://example.com:8443/"
+        );
+        assert_eq!(
+            redirect_location(8443, "[::1]:8080", "/about").await,
+            "https://[::1]:8443/about"
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::header::HOST;
+use axum::http::Request;
+use axum::response::{IntoResponse, Response};
+use axum::routing::any;
+use axum::Router;
+use hyper::StatusCode;
+use log::{debug, info};
+
+use crate::mount::{serve_mounted, MountTable};
+use crate::SharedState;
+
+/// Pattern matching every host. Used for the site that serves requests no other pattern matches.
+pub const DEFAULT_HOST_PATTERN: &str = "*";
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum HostPattern {
+    /// Matches the host name exactly.
+    Exact(String),
+    /// Matches any subdomain of the host name, but not the host name itself. Written as '*.example.com'.
+    Subdomain(String),
+    Any,
+}
+
+impl HostPattern {
+    pub fn parse(text: &str) -> Self {
+        let text = text.trim().to_lowercase();
+
+        if text == DEFAULT_HOST_PATTERN {
+            return HostPattern::Any;
+        }
+
+        match text.strip_prefix("*.") {
+            Some(domain) => HostPattern::Subdomain(domain.to_string()),
+            None => HostPattern::Exact(text),
+        }
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => name == host,
+            HostPattern::Subdomain(domain) => host
+                .strip_suffix(domain.as_str())
+                .map(|sub| sub.len() > 1 && sub.ends_with('.'))
+                .unwrap_or(false),
+            HostPattern::Any => true,
+        }
+    }
+
+    // exact names first, then subdomains with the most specific domain first
+    fn rank(&self) -> (usize, usize) {
+        match self {
+            HostPattern::Exact(name) => (0, usize::MAX - name.len()),
+            HostPattern::Subdomain(domain) => (1, usize::MAX - domain.len()),
+            HostPattern::Any => (2, 0),
+        }
+    }
+}
+
+impl Display for HostPattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostPattern::Exact(name) => f.write_str(name),
+            HostPattern::Subdomain(domain) => write!(f, "*.{}", domain),
+            HostPattern::Any => f.write_str(DEFAULT_HOST_PATTERN),
+        }
+    }
+}
+
+/// Separately compiled sites chosen by the request's Host header.
+/// Each host has its own mount table, so a host may serve several sites under URL prefixes.
+#[derive(Clone, Default)]
+pub struct HostTable {
+    // kept sorted by most specific pattern first
+    hosts: Vec<(HostPattern, MountTable)>,
+}
+
+impl HostTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves a site at the root of hosts matching the pattern. Replaces any site mounted there.
+    pub fn host<T: AsRef<str>>(&mut self, pattern: T, state: Arc<SharedState>) {
+        self.mount(pattern, "/", state);
+    }
+
+    /// Serves a site under the prefix for hosts matching the pattern.
+    /// Replaces any site already mounted at the same prefix of the same pattern.
+    pub fn mount<T: AsRef<str>, P: AsRef<str>>(
+        &mut self,
+        pattern: T,
+        prefix: P,
+        state: Arc<SharedState>,
+    ) {
+        let pattern = HostPattern::parse(pattern.as_ref());
+
+        info!("Serving site for host pattern {:?}", pattern);
+
+        match self.hosts.iter_mut().find(|(p, _)| p == &pattern) {
+            Some((_, table)) => table.mount(prefix, state),
+            None => {
+                let mut table = MountTable::new();
+                table.mount(prefix, state);
+
+                self.hosts.push((pattern, table));
+                self.hosts.sort_by_key(|(p, _)| p.rank());
+            }
+        }
+    }
+
+    pub fn hosts(&self) -> &Vec<(HostPattern, MountTable)> {
+        &self.hosts
+    }
+
+    /// Finds the mount table for a host name and the pattern that matched it. Any port is ignored.
+    pub fn find(&self, host: &str) -> Option<(&HostPattern, &MountTable)> {
+        let host = strip_port(host).trim_end_matches('.').to_lowercase();
+
+        self.hosts
+            .iter()
+            .find(|(p, _)| p.matches(&host))
+            .map(|(pattern, table)| (pattern, table))
+    }
+}
+
+pub(crate) fn strip_port(host: &str) -> &str {
+    // bracketed IPv6 addresses contain ':' themselves
+    match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or(rest),
+        None => host.split(':').next().unwrap_or(host),
+    }
+}
+
+/// Parses a 'host=path' or 'host/prefix=path' command line argument into the host, mount prefix and path.
+/// The prefix is '/' when none is given.
+pub fn parse_host(text: &str) -> Result<(String, String, PathBuf), String> {
+    let error = || format!("Expected virtual host in form 'host=path'. Found {:?}", text);
+
+    let (host, path) = text.split_once('=').ok_or_else(error)?;
+    let (host, prefix) = host.trim().split_once('/').unwrap_or((host.trim(), ""));
+
+    match host.is_empty() || path.trim().is_empty() {
+        true => Err(error()),
+        false => Ok((host.to_string(), format!("/{}", prefix), PathBuf::from(path.trim()))),
+    }
+}
+
+/// Creates a router that sends each request to the site matching its Host header.
+pub fn create_host_router(table: HostTable) -> Router {
+    Router::new()
+        .route("/", any(host_handler))
+        .route("/*path", any(host_handler))
+        .with_state(Arc::new(table))
+}
+
+pub async fn host_handler(
+    State(table): State<Arc<HostTable>>,
+    request: Request<Body>,
+) -> Response {
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| request.uri().host())
+        .unwrap_or_default()
+        .to_string();
+
+    match table.find(&host) {
+        None => {
+            info!("No site configured for host \"{}\"", host);
+            StatusCode::MISDIRECTED_REQUEST.into_response()
+        }
+        Some((pattern, mounts)) => {
+            debug!("Dispatching request for host \"{}\"", host);
+            serve_mounted(mounts, &pattern.to_string(), request).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_host_splits_pattern_and_path() {
+        assert_eq!(
+            parse_host(" *.example.com = sites/blog "),
+            Ok((
+                String::from("*.example.com"),
+                String::from("/"),
+                PathBuf::from("sites/blog")
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_host_splits_mount_prefix() {
+        assert_eq!(
+            parse_host("example.com/docs/api=sites/api"),
+            Ok((
+                String::from("example.com"),
+                String::from("/docs/api"),
+                PathBuf::from("sites/api")
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_host_requires_host_and_path() {
+        assert!(parse_host("example.com").is_err());
+        assert!(parse_host("=sites/blog").is_err());
+        assert!(parse_host("/docs=sites/docs").is_err());
+        assert!(parse_host("example.com=").is_err());
+    }
+
+    #[test]
+    fn subdomain_pattern_does_not_match_domain_itself() {
+        let pattern = HostPattern::parse("*.Example.com");
+
+        assert_eq!(pattern, HostPattern::Subdomain(String::from("example.com")));
+        assert!(pattern.matches("blog.example.com"));
+        assert!(!pattern.matches("example.com"));
+        assert!(!pattern.matches("badexample.com"));
+    }
+
+    #[test]
+    fn display_matches_parsed_text() {
+        for text in ["example.com", "*.example.com", DEFAULT_HOST_PATTERN] {
+            assert_eq!(HostPattern::parse(text).to_string(), text);
+        }
+    }
+
+    #[test]
+    fn strip_port_keeps_ipv6_address() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "::1");
+        assert_eq!(strip_port("example.com"), "example.com");
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::Request;
+use axum::response::Response;
+use axum::routing::any;
+use axum::Router;
+use log::{debug, error, info};
+
+use crate::config::ServerConfig;
+use crate::incremental::SiteCompiler;
+use crate::metrics::metrics;
+use crate::{find_source_files, serve_request, SharedState};
+
+/// State that can be replaced while the server is running. Requests use the build that was current when they arrived.
+pub struct LiveState {
+    current: RwLock<Arc<SharedState>>,
+}
+
+impl LiveState {
+    pub fn new(state: Arc<SharedState>) -> Self {
+        Self {
+            current: RwLock::new(state),
+        }
+    }
+
+    pub fn current(&self) -> Arc<SharedState> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, state: Arc<SharedState>) {
+        *self.current.write().unwrap() = state;
+    }
+}
+
+/// Creates a router that sends every request to the current state.
+pub fn create_live_router(live: Arc<LiveState>) -> Router {
+    Router::new()
+        .route("/", any(live_handler))
+        .route("/*path", any(live_handler))
+        .with_state(live)
+}
+
+pub async fn live_handler(State(live): State<Arc<LiveState>>, request: Request<Body>) -> Response {
+    serve_request(State(live.current()), request).await
+}
+
+/// Checks the serve path for changed, added and removed files at each interval,
+/// recompiling only those files and replacing the live state afterwards.
+pub async fn watch_sources(
+    live: Arc<LiveState>,
+    mut compiler: SiteCompiler,
+    serve_path: PathBuf,
+    settings: ServerConfig,
+    interval: Duration,
+) {
+    let mut modified = match scan(&serve_path, &settings) {
+        Err(e) => {
+            error!("Failed to scan {:?}. Not watching for changes. Reason: {}", serve_path, e);
+            return;
+        }
+        Ok(m) => m,
+    };
+
+    info!("Watching {:?} for changes", serve_path);
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let current = match scan(&serve_path, &settings) {
+            Err(e) => {
+                error!("Failed to scan {:?}. Reason: {}", serve_path, e);
+                continue;
+            }
+            Ok(m) => m,
+        };
+
+        let mut changed = false;
+
+        for (path, time) in current.iter() {
+            if modified.get(path) == Some(time) {
+                continue;
+            }
+
+            changed = true;
+
+            let text = match std::fs::read_to_string(path) {
+                Err(e) => {
+                    error!("Failed to read {:?}. Reason: {}", path, e);
+                    continue;
+                }
+                Ok(t) => t,
+            };
+
+            info!("Recompiling {:?}", path);
+            match compiler.update_file(path.clone(), text) {
+                Err(e) => {
+                    error!("Failed to compile {:?}. Keeping its last build. Reason: {}", path, e);
+                }
+                Ok(dependents) => {
+                    if !dependents.is_empty() {
+                        debug!("Files using changed definitions: {:?}", dependents);
+                    }
+                }
+            }
+        }
+
+        for path in modified.keys().filter(|p| !current.contains_key(*p)) {
+            changed = true;
+
+            info!("Removing {:?}", path);
+            compiler.remove_file(path);
+        }
+
+        modified = current;
+
+        if changed {
+            metrics().record_reload();
+            live.replace(Arc::new(compiler.to_state().with_settings(settings.clone())));
+        }
+    }
+}
+
+fn scan(
+    serve_path: &PathBuf,
+    settings: &ServerConfig,
+) -> Result<HashMap<PathBuf, SystemTime>, String> {
+    let mut modified = HashMap::new();
+
+    for path in find_source_files(serve_path, &settings.include, &settings.exclude)? {
+        let time = std::fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .or_else(|e| Err(format!("Failed to read metadata of {:?}. Reason: {}", path, e)))?;
+
+        modified.insert(path, time);
+    }
+
+    Ok(modified)
+}
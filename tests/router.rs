@@ -0,0 +1,812 @@
+//! Requests sent through the embeddable router, covering how pages, config and server settings are served.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use axum::body::{Body, HttpBody};
+use axum::http::header::{
+    AsHeaderName, ACCEPT_ENCODING, ACCEPT_LANGUAGE, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE,
+    ETAG, HOST, IF_NONE_MATCH, VARY,
+};
+use axum::http::{HeaderMap, Method, Request, StatusCode};
+use axum::routing;
+use axum::Router;
+use garnish_lang::GarnishData;
+use tower::ServiceExt;
+
+use garnish_web_server::cache::is_not_modified;
+use garnish_web_server::compression::with_compression;
+use garnish_web_server::config::{
+    CacheRule, CompressionConfig, MetricsConfig, ProbeConfig, RewriteRule, ScriptValue,
+    ServerConfig,
+};
+use garnish_web_server::metrics::with_metrics;
+use garnish_web_server::mount::{create_mount_router, MountTable};
+use garnish_web_server::native::NativeFunction;
+use garnish_web_server::probe::with_probes;
+use garnish_web_server::vhost::{create_host_router, HostTable};
+use garnish_web_server::{create_router, WebServerBuilder};
+
+struct TestResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: String,
+}
+
+/// Empty directory for a test's site files.
+fn site_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "garnish-web-server-test-{}-{}",
+        name,
+        std::process::id()
+    ));
+
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+fn write(dir: &Path, path: &str, text: &str) {
+    let path = dir.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, text).unwrap();
+}
+
+/// Source of a page whose body is the given text.
+fn page(text: &str) -> String {
+    format!("html` body` text` \"{}\"", text)
+}
+
+fn request(method: Method, uri: &str) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn get(uri: &str) -> Request<Body> {
+    request(Method::GET, uri)
+}
+
+async fn send(router: Router, request: Request<Body>) -> TestResponse {
+    let response = router.oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+
+    let mut body = response.into_body();
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.unwrap());
+    }
+
+    TestResponse {
+        status,
+        headers,
+        body: String::from_utf8_lossy(&bytes).to_string(),
+    }
+}
+
+fn header(response: &TestResponse, name: impl AsHeaderName) -> Option<&str> {
+    response.headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+#[tokio::test]
+async fn routes_requests_by_path_and_method() {
+    let router = WebServerBuilder::new()
+        .source(
+            "index.garnish",
+            format!(
+                "{}\n\n@Method \"POST\" {{\n    {}\n}}\n",
+                page("Home"),
+                page("Posted")
+            ),
+        )
+        .source("about/index.garnish", page("About"))
+        .source("contact.garnish", page("Contact"))
+        .router()
+        .unwrap();
+
+    let home = send(router.clone(), get("/")).await;
+    assert_eq!(home.status, StatusCode::OK);
+    assert!(home.body.contains("Home"), "{}", home.body);
+
+    let about = send(router.clone(), get("/about")).await;
+    assert_eq!(about.status, StatusCode::OK);
+    assert!(about.body.contains("About"), "{}", about.body);
+
+    let contact = send(router.clone(), get("/contact/")).await;
+    assert!(contact.body.contains("Contact"), "{}", contact.body);
+
+    let posted = send(router.clone(), request(Method::POST, "/")).await;
+    assert!(posted.body.contains("Posted"), "{}", posted.body);
+
+    let missing = send(router, get("/missing")).await;
+    assert_eq!(missing.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn config_file_values_reach_scripts() {
+    let dir = site_dir("config-values");
+    write(
+        &dir,
+        "garnish-web.toml",
+        "[script.values]\ntitle = \"From file\"\n",
+    );
+    write(&dir, "index.garnish", "html` body` text` config.title");
+
+    let router = WebServerBuilder::new().serve_path(&dir).router().unwrap();
+
+    let response = send(router, get("/")).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.contains("From file"), "{}", response.body);
+}
+
+#[tokio::test]
+async fn builder_values_take_priority_over_environment_and_file() {
+    let dir = site_dir("config-priority");
+    write(
+        &dir,
+        "garnish-web.toml",
+        "[script]\nenv_prefix = \"GARNISH_WEB_ROUTER_TEST_\"\nexpose = [\"*\"]\n\n\
+         [script.values]\nfrom_env = \"file\"\nfrom_define = \"file\"\n",
+    );
+    write(
+        &dir,
+        "index.garnish",
+        "html` body` (text` config.from_env) <> (text` config.from_define)",
+    );
+    std::env::set_var("GARNISH_WEB_ROUTER_TEST_FROM_ENV", "env");
+    std::env::set_var("GARNISH_WEB_ROUTER_TEST_FROM_DEFINE", "env");
+
+    let router = WebServerBuilder::new()
+        .serve_path(&dir)
+        .value("from_define", ScriptValue::Text(String::from("define")))
+        .router()
+        .unwrap();
+
+    let response = send(router, get("/")).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.contains("env"), "{}", response.body);
+    assert!(response.body.contains("define"), "{}", response.body);
+    assert!(!response.body.contains("file"), "{}", response.body);
+}
+
+#[tokio::test]
+async fn ignore_file_and_default_patterns_exclude_sources() {
+    let dir = site_dir("ignore-file");
+    write(&dir, ".garnishignore", "drafts/\n");
+    write(&dir, "index.garnish", &page("Home"));
+    write(&dir, "drafts/post.garnish", &page("Draft"));
+    write(&dir, ".hidden/page.garnish", &page("Hidden"));
+    write(&dir, "node_modules/package/page.garnish", &page("Package"));
+
+    let router = WebServerBuilder::new().serve_path(&dir).router().unwrap();
+
+    assert_eq!(send(router.clone(), get("/")).await.status, StatusCode::OK);
+
+    for path in [
+        "/drafts/post",
+        "/.hidden/page",
+        "/node_modules/package/page",
+    ] {
+        assert_eq!(
+            send(router.clone(), get(path)).await.status,
+            StatusCode::NOT_FOUND,
+            "{}",
+            path
+        );
+    }
+}
+
+#[tokio::test]
+async fn configured_excludes_keep_default_patterns() {
+    let dir = site_dir("configured-excludes");
+    write(&dir, "index.garnish", &page("Home"));
+    write(&dir, "secret.garnish", &page("Secret"));
+    write(&dir, "node_modules/package/page.garnish", &page("Package"));
+    write(&dir, ".hidden/page.garnish", &page("Hidden"));
+    write(&dir, ".well-known/page.garnish", &page("Well known"));
+
+    let settings = ServerConfig {
+        exclude: vec![
+            String::from("secret.garnish"),
+            String::from("!.well-known/"),
+        ],
+        ..ServerConfig::default()
+    };
+
+    let router = WebServerBuilder::new()
+        .serve_path(&dir)
+        .settings(settings)
+        .router()
+        .unwrap();
+
+    assert_eq!(send(router.clone(), get("/")).await.status, StatusCode::OK);
+    assert_eq!(
+        send(router.clone(), get("/.well-known/page")).await.status,
+        StatusCode::OK
+    );
+
+    for path in ["/secret", "/node_modules/package/page", "/.hidden/page"] {
+        assert_eq!(
+            send(router.clone(), get(path)).await.status,
+            StatusCode::NOT_FOUND,
+            "{}",
+            path
+        );
+    }
+}
+
+#[tokio::test]
+async fn mounts_use_the_longest_matching_prefix() {
+    let site = |name: &str| {
+        WebServerBuilder::new()
+            .source("index.garnish", page(name))
+            .source("docsx.garnish", page(&format!("{} docsx", name)))
+            .build()
+            .unwrap()
+    };
+
+    let mut table = MountTable::new();
+    table.mount("/", site("Root"));
+    table.mount("/docs", site("Docs"));
+    table.mount("/docs/api/", site("Api"));
+    let router = create_mount_router(table);
+
+    let api = send(router.clone(), get("/docs/api")).await;
+    assert!(api.body.contains("Api"), "{}", api.body);
+
+    let docs = send(router.clone(), get("/docs/")).await;
+    assert!(docs.body.contains("Docs"), "{}", docs.body);
+
+    // prefixes only match whole path segments
+    let root = send(router.clone(), get("/docsx")).await;
+    assert!(root.body.contains("Root docsx"), "{}", root.body);
+
+    let missing = send(router, get("/docs/missing")).await;
+    assert_eq!(missing.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn unknown_mount_prefix_is_not_found() {
+    let mut table = MountTable::new();
+    table.mount(
+        "/docs",
+        WebServerBuilder::new()
+            .source("index.garnish", page("Docs"))
+            .build()
+            .unwrap(),
+    );
+
+    let response = send(create_mount_router(table), get("/admin")).await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn virtual_hosts_match_by_host_header() {
+    let site = |name: &str| {
+        WebServerBuilder::new()
+            .source("index.garnish", page(name))
+            .build()
+            .unwrap()
+    };
+
+    let mut table = HostTable::new();
+    table.host("example.com", site("Main"));
+    table.host("*.example.com", site("Subdomain"));
+    let router = create_host_router(table.clone());
+
+    let host_request = |host: &str| {
+        Request::builder()
+            .uri("/")
+            .header(HOST, host)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let main = send(router.clone(), host_request("Example.com:8080")).await;
+    assert!(main.body.contains("Main"), "{}", main.body);
+
+    let subdomain = send(router.clone(), host_request("blog.example.com")).await;
+    assert!(subdomain.body.contains("Subdomain"), "{}", subdomain.body);
+
+    let unknown = send(router, host_request("example.org")).await;
+    assert_eq!(unknown.status, StatusCode::MISDIRECTED_REQUEST);
+
+    table.host("*", site("Default"));
+    let fallback = send(create_host_router(table), host_request("example.org")).await;
+    assert!(fallback.body.contains("Default"), "{}", fallback.body);
+}
+
+#[tokio::test]
+async fn virtual_hosts_have_their_own_mounts() {
+    let site = |name: &str| {
+        WebServerBuilder::new()
+            .source("index.garnish", page(name))
+            .build()
+            .unwrap()
+    };
+
+    let mut table = HostTable::new();
+    table.host("example.com", site("Main"));
+    table.mount("example.com", "/docs", site("Docs"));
+    table.mount("admin.example.com", "/admin", site("Admin"));
+    let router = create_host_router(table);
+
+    let host_request = |host: &str, uri: &str| {
+        Request::builder()
+            .uri(uri)
+            .header(HOST, host)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let main = send(router.clone(), host_request("example.com", "/")).await;
+    assert!(main.body.contains("Main"), "{}", main.body);
+
+    let docs = send(router.clone(), host_request("example.com", "/docs")).await;
+    assert!(docs.body.contains("Docs"), "{}", docs.body);
+
+    // mounts of one host aren't served for another
+    let other = send(router.clone(), host_request("admin.example.com", "/docs")).await;
+    assert_eq!(other.status, StatusCode::NOT_FOUND);
+
+    let admin = send(router, host_request("admin.example.com", "/admin")).await;
+    assert!(admin.body.contains("Admin"), "{}", admin.body);
+}
+
+#[tokio::test]
+async fn cached_routes_answer_matching_validators_with_not_modified() {
+    let mut settings = ServerConfig::default();
+    settings.cache.rules = vec![CacheRule::new("index", 60)];
+
+    let router = WebServerBuilder::new()
+        .source("index.garnish", page("Home"))
+        .settings(settings)
+        .router()
+        .unwrap();
+
+    let first = send(router.clone(), get("/")).await;
+    assert_eq!(first.status, StatusCode::OK);
+    assert_eq!(header(&first, CACHE_CONTROL), Some("public, max-age=60"));
+    let tag = header(&first, ETAG).unwrap().to_string();
+
+    let mut revalidate = get("/");
+    revalidate
+        .headers_mut()
+        .insert(IF_NONE_MATCH, tag.parse().unwrap());
+    let second = send(router.clone(), revalidate).await;
+    assert_eq!(second.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(header(&second, ETAG), Some(tag.as_str()));
+    assert!(second.body.is_empty());
+
+    let mut changed = get("/");
+    changed
+        .headers_mut()
+        .insert(IF_NONE_MATCH, "\"other\"".parse().unwrap());
+    assert_eq!(send(router, changed).await.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn entity_tags_are_the_same_across_builds() {
+    let tag = || async {
+        let router = WebServerBuilder::new()
+            .source("index.garnish", page("Home"))
+            .router()
+            .unwrap();
+
+        header(&send(router, get("/")).await, ETAG).map(|t| t.to_string())
+    };
+
+    let first = tag().await;
+    assert!(first.is_some());
+    assert_eq!(first, tag().await);
+}
+
+#[tokio::test]
+async fn unsafe_methods_with_matching_validators_fail_precondition() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+
+    let router = WebServerBuilder::new()
+        .source(
+            "index.garnish",
+            format!(
+                "{}\n\n@Method \"POST\" {{\n    record` {}\n}}\n",
+                page("Home"),
+                page("Posted")
+            ),
+        )
+        .native(NativeFunction::new("record", move |input, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(input)
+        }))
+        .router()
+        .unwrap();
+
+    let mut post = request(Method::POST, "/");
+    post.headers_mut()
+        .insert(IF_NONE_MATCH, "*".parse().unwrap());
+
+    let response = send(router, post).await;
+    assert_eq!(response.status, StatusCode::PRECONDITION_FAILED);
+    // the route didn't run, so its side effects didn't happen
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn pages_call_native_functions() {
+    let router = WebServerBuilder::new()
+        .source("index.garnish", "html` body` text` greeting` ()")
+        .native(NativeFunction::new("greeting", |_, data| {
+            data.start_char_list()?;
+            for c in "Hello from Rust".chars() {
+                data.add_to_char_list(c)?;
+            }
+            Ok(data.end_char_list()?)
+        }))
+        .router()
+        .unwrap();
+
+    let response = send(router, get("/")).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.contains("Hello from Rust"));
+}
+
+#[test]
+fn natives_named_like_a_definition_are_rejected() {
+    let result = WebServerBuilder::new()
+        .source("index.garnish", page("Home"))
+        .native(NativeFunction::new("text", |input, _| Ok(input)))
+        .build();
+
+    match result {
+        Ok(_) => panic!("native shadowed by a definition was registered"),
+        Err(e) => assert!(e.contains("\"text\"")),
+    }
+}
+
+#[tokio::test]
+async fn cache_control_annotation_sets_cache_control() {
+    let mut settings = ServerConfig::default();
+    settings.cache.rules = vec![CacheRule::new("cached", 60)];
+
+    let router = WebServerBuilder::new()
+        .source(
+            "index.garnish",
+            format!("@CacheControl private, max-age=30\n\n{}", page("Home")),
+        )
+        .source(
+            "cached.garnish",
+            format!("@CacheControl no-cache\n\n{}", page("Cached")),
+        )
+        .settings(settings)
+        .router()
+        .unwrap();
+
+    let annotated = send(router.clone(), get("/")).await;
+    assert_eq!(
+        header(&annotated, CACHE_CONTROL),
+        Some("private, max-age=30")
+    );
+
+    // the annotation replaces the policy a rule would otherwise generate
+    let cached = send(router, get("/cached")).await;
+    assert_eq!(header(&cached, CACHE_CONTROL), Some("no-cache"));
+}
+
+#[test]
+fn cache_annotation_opts_routes_into_caching() {
+    let state = WebServerBuilder::new()
+        .source(
+            "index.garnish",
+            format!(
+                "@Cache 300 query Accept-Language\n@CacheControl no-cache\n\n{}\n\n@Method \"POST\" {{\n    {}\n}}\n",
+                page("Home"),
+                page("Posted")
+            ),
+        )
+        .source("about.garnish", page("About"))
+        .build()
+        .unwrap();
+
+    let mut expected = CacheRule::new("index", 300);
+    expected.vary_query = true;
+    expected.vary_headers = vec![String::from("Accept-Language")];
+
+    let routes = state.route_mapping();
+    assert_eq!(routes["index"].cache_rule(), Some(&expected));
+    assert_eq!(routes["POST@index"].cache_rule(), Some(&expected));
+    assert_eq!(
+        routes["index"].cache_control(),
+        Some(&String::from("no-cache"))
+    );
+    assert_eq!(routes["about"].cache_rule(), None);
+}
+
+#[test]
+fn cache_annotation_needs_a_time_to_live() {
+    let result = WebServerBuilder::new()
+        .source(
+            "index.garnish",
+            format!("@Cache forever\n\n{}", page("Home")),
+        )
+        .build();
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn cached_responses_list_the_headers_they_vary_by() {
+    let router = WebServerBuilder::new()
+        .source(
+            "index.garnish",
+            format!("@Cache 60 accept-language\n\n{}", page("Home")),
+        )
+        .router()
+        .unwrap();
+
+    let mut request = get("/");
+    request
+        .headers_mut()
+        .insert(ACCEPT_LANGUAGE, "en".parse().unwrap());
+
+    let first = send(router.clone(), request).await;
+    assert_eq!(first.status, StatusCode::OK);
+    assert_eq!(header(&first, VARY), Some("accept-language"));
+    assert_eq!(header(&first, CACHE_CONTROL), Some("public, max-age=60"));
+
+    let mut request = get("/");
+    request
+        .headers_mut()
+        .insert(ACCEPT_LANGUAGE, "en".parse().unwrap());
+
+    let cached = send(router, request).await;
+    assert_eq!(header(&cached, VARY), Some("accept-language"));
+    assert_eq!(header(&cached, ETAG), header(&first, ETAG));
+}
+
+#[tokio::test]
+async fn rewrites_change_the_matched_route() {
+    let settings = ServerConfig {
+        rewrites: vec![RewriteRule::new("blog/*", "posts/*")],
+        ..ServerConfig::default()
+    };
+
+    let router = WebServerBuilder::new()
+        .source("posts/first.garnish", page("First post"))
+        .settings(settings)
+        .router()
+        .unwrap();
+
+    let response = send(router, get("/blog/first?ref=home")).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.contains("First post"), "{}", response.body);
+}
+
+#[tokio::test]
+async fn rewrites_apply_to_static_files() {
+    let dir = site_dir("static-rewrite");
+    write(&dir, "static/robots.txt", "User-agent: *\n");
+
+    let settings = ServerConfig {
+        static_dirs: vec![PathBuf::from("static")],
+        rewrites: vec![RewriteRule::new("legacy/robots", "robots.txt")],
+        ..ServerConfig::default()
+    };
+
+    let router = WebServerBuilder::new()
+        .serve_path(&dir)
+        .settings(settings)
+        .router()
+        .unwrap();
+
+    let response = send(router.clone(), get("/legacy/robots")).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.body, "User-agent: *\n");
+}
+
+fn precompressed_router(name: &str, settings: ServerConfig) -> Router {
+    let dir = site_dir(name);
+    write(&dir, "static/main.css", "plain");
+    write(&dir, "static/main.css.br", "brotli");
+    write(&dir, "static/main.css.gz", "gzip");
+
+    let mut settings = settings;
+    settings.static_dirs = vec![PathBuf::from("static")];
+
+    WebServerBuilder::new()
+        .serve_path(&dir)
+        .settings(settings)
+        .router()
+        .unwrap()
+}
+
+async fn get_encoded(router: Router, accept_encoding: &str) -> TestResponse {
+    let mut request = get("/main.css");
+    request
+        .headers_mut()
+        .insert(ACCEPT_ENCODING, accept_encoding.parse().unwrap());
+
+    send(router, request).await
+}
+
+#[tokio::test]
+async fn precompressed_sidecars_follow_client_preferences() {
+    let router = precompressed_router("precompressed-preferences", ServerConfig::default());
+
+    let cases = [
+        ("gzip, br", Some("br"), "brotli"),
+        ("br;q=0.5, gzip", Some("gzip"), "gzip"),
+        ("br;q=0, gzip", Some("gzip"), "gzip"),
+        ("*, br;q=0", Some("gzip"), "gzip"),
+        ("identity", None, "plain"),
+        ("gzip;q=0, br;q=0", None, "plain"),
+    ];
+
+    for (accept_encoding, encoding, body) in cases {
+        let response = get_encoded(router.clone(), accept_encoding).await;
+
+        assert_eq!(response.status, StatusCode::OK, "{}", accept_encoding);
+        assert_eq!(
+            header(&response, CONTENT_ENCODING),
+            encoding,
+            "{}",
+            accept_encoding
+        );
+        assert_eq!(response.body, body, "{}", accept_encoding);
+    }
+}
+
+#[tokio::test]
+async fn precompressed_sidecars_respect_compression_settings() {
+    let mut settings = ServerConfig::default();
+    settings.compression.br = false;
+    let router = precompressed_router("precompressed-no-br", settings);
+
+    let response = get_encoded(router, "br, gzip").await;
+    assert_eq!(header(&response, CONTENT_ENCODING), Some("gzip"));
+
+    let mut settings = ServerConfig::default();
+    settings.compression.enabled = false;
+    let router = precompressed_router("precompressed-disabled", settings);
+
+    let response = get_encoded(router, "br, gzip").await;
+    assert_eq!(header(&response, CONTENT_ENCODING), None);
+    assert_eq!(response.body, "plain");
+}
+
+/// Router with a compressible page that has an entity tag, answering matching validators with 304.
+fn tagged_router() -> Router {
+    let page = Router::new().route(
+        "/",
+        routing::get(|request: Request<Body>| async move {
+            let headers = [(ETAG, "\"abc\""), (CONTENT_TYPE, "text/html")];
+
+            match is_not_modified(&request, "\"abc\"", None) {
+                true => (StatusCode::NOT_MODIFIED, headers, String::new()),
+                false => (StatusCode::OK, headers, "page ".repeat(1000)),
+            }
+        }),
+    );
+
+    with_compression(page, &CompressionConfig::default())
+}
+
+#[tokio::test]
+async fn compressed_responses_get_their_own_entity_tag() {
+    let mut request = get("/");
+    request
+        .headers_mut()
+        .insert(ACCEPT_ENCODING, "gzip".parse().unwrap());
+
+    let response = send(tagged_router(), request).await;
+    assert_eq!(header(&response, CONTENT_ENCODING), Some("gzip"));
+    assert_eq!(header(&response, ETAG), Some("\"abc-gzip\""));
+
+    let response = send(tagged_router(), get("/")).await;
+    assert_eq!(header(&response, CONTENT_ENCODING), None);
+    assert_eq!(header(&response, ETAG), Some("\"abc\""));
+}
+
+#[tokio::test]
+async fn encoded_entity_tags_match_the_unencoded_response() {
+    let mut request = get("/");
+    request
+        .headers_mut()
+        .insert(ACCEPT_ENCODING, "gzip".parse().unwrap());
+    request
+        .headers_mut()
+        .insert(IF_NONE_MATCH, "\"abc-gzip\"".parse().unwrap());
+
+    let response = send(tagged_router(), request).await;
+    assert_eq!(response.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(header(&response, ETAG), Some("\"abc-gzip\""));
+}
+
+fn probed_router(builder: WebServerBuilder) -> Router {
+    let probes = ProbeConfig {
+        enabled: true,
+        ..ProbeConfig::default()
+    };
+    let state = builder.allow_build_errors().build().unwrap();
+
+    with_probes(create_router(state.clone()), state, &probes)
+}
+
+#[tokio::test]
+async fn probes_report_a_site_that_compiled() {
+    let router = probed_router(
+        WebServerBuilder::new()
+            .source("index.garnish", page("Home"))
+            .source("about.garnish", page("About")),
+    );
+
+    let health = send(router.clone(), get("/_health")).await;
+    assert_eq!(health.status, StatusCode::OK);
+
+    let ready = send(router.clone(), get("/_ready")).await;
+    assert_eq!(ready.status, StatusCode::OK);
+
+    let info = send(router, get("/_info")).await;
+    let info: serde_json::Value = serde_json::from_str(&info.body).unwrap();
+    assert_eq!(info["ready"], true);
+    assert_eq!(info["sites"][0]["routes"], 2);
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+}
+
+#[tokio::test]
+async fn ready_probe_reports_files_that_failed_to_compile() {
+    let router = probed_router(
+        WebServerBuilder::new()
+            .source("index.garnish", page("Home"))
+            .source("broken.garnish", "html` ( ( body` \"Broken\""),
+    );
+
+    let health = send(router.clone(), get("/_health")).await;
+    assert_eq!(health.status, StatusCode::OK);
+
+    let ready = send(router.clone(), get("/_ready")).await;
+    assert_eq!(ready.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(ready.body.contains("broken.garnish"), "{}", ready.body);
+
+    let info = send(router.clone(), get("/_info")).await;
+    let info: serde_json::Value = serde_json::from_str(&info.body).unwrap();
+    assert_eq!(info["ready"], false);
+    assert_eq!(info["sites"][0]["routes"], 1);
+
+    // the rest of the site is still served
+    let missing = send(router, get("/broken")).await;
+    assert_eq!(missing.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn metrics_endpoint_counts_requests_by_route() {
+    let metrics = MetricsConfig {
+        enabled: true,
+        ..MetricsConfig::default()
+    };
+    let state = WebServerBuilder::new()
+        .source("counted.garnish", page("Counted"))
+        .build()
+        .unwrap();
+    let router = with_metrics(create_router(state), &metrics);
+
+    // metrics are global to the process, so the route's name is only used by this test
+    send(router.clone(), get("/counted")).await;
+    send(router.clone(), get("/counted")).await;
+
+    let response = send(router, get("/metrics")).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(
+        response.body.lines().any(|l| l
+            .starts_with("garnish_web_requests_total{site=\"\",route=\"counted\"")
+            && l.ends_with(" 2")),
+        "{}",
+        response.body
+    );
+}